@@ -0,0 +1,212 @@
+use crate::CollisionPolicy;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 把输出依次追加到tar文件中，代替在输出目录中逐个写入文件
+///
+/// 使用GNU tar格式，超过100字节的条目名用`././@LongLink`条目保存，常见的解压工具都支持
+pub struct TarArchive {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    file: File,
+    // 已占用的条目名，tar中同名条目解压时后者会覆盖前者
+    names: HashSet<String>,
+}
+
+const BLOCK: usize = 512;
+
+impl TarArchive {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(TarArchive {
+            inner: Mutex::new(Inner {
+                file: File::create(path)?,
+                names: HashSet::new(),
+            }),
+        })
+    }
+
+    /// 条目名是否已被占用
+    pub fn contains(&self, name: &str) -> bool {
+        self.inner.lock().unwrap().names.contains(name)
+    }
+
+    /// 按策略占用条目名，返回`None`表示名字已被占用且策略不允许写入
+    ///
+    /// 与[`crate::claim_output_path`]相同，`Rename`时在扩展名前追加计数器
+    pub fn claim(&self, name: &str, policy: CollisionPolicy) -> Option<String> {
+        let names = &mut self.inner.lock().unwrap().names;
+        if names.insert(name.to_string()) || policy == CollisionPolicy::Overwrite {
+            return Some(name.to_string());
+        }
+        if policy != CollisionPolicy::Rename {
+            return None;
+        }
+        let file_start = name.rfind('/').map_or(0, |i| i + 1);
+        let (stem, ext) = match name[file_start..].rfind('.') {
+            Some(dot) => name.split_at(file_start + dot),
+            None => (name, ""),
+        };
+        (1..)
+            .map(|counter| format!("{}_{}{}", stem, counter, ext))
+            .find(|candidate| names.insert(candidate.clone()))
+    }
+
+    /// 追加一个条目并落盘，写入失败时截断回原来的长度
+    ///
+    /// `mtime`为`None`时使用当前时间
+    pub fn append(
+        &self,
+        name: &str,
+        data: &[u8],
+        mtime: Option<SystemTime>,
+    ) -> std::io::Result<()> {
+        let mtime = mtime
+            .unwrap_or_else(SystemTime::now)
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut buf = Vec::with_capacity(data.len() + 3 * BLOCK);
+        if name.len() > 100 {
+            let mut long_name = name.as_bytes().to_vec();
+            long_name.push(0);
+            buf.extend(header(
+                b"././@LongLink",
+                long_name.len() as u64,
+                mtime,
+                b'L',
+            )?);
+            push_padded(&mut buf, &long_name);
+        }
+        buf.extend(header(name.as_bytes(), data.len() as u64, mtime, b'0')?);
+        push_padded(&mut buf, data);
+
+        let inner = &mut self.inner.lock().unwrap();
+        let len = inner.file.metadata()?.len();
+        inner
+            .file
+            .write_all(&buf)
+            .and_then(|_| inner.file.sync_data())
+            .inspect_err(|_| {
+                let _ = inner.file.set_len(len);
+            })?;
+        inner.names.insert(name.to_string());
+        Ok(())
+    }
+
+    /// 写入两个全零块作为结束标记
+    pub fn finish(self) -> std::io::Result<()> {
+        let mut file = self.inner.into_inner().unwrap().file;
+        file.write_all(&[0; 2 * BLOCK])?;
+        file.sync_all()
+    }
+}
+
+// 数据补齐到块大小
+fn push_padded(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(data);
+    buf.resize(buf.len().next_multiple_of(BLOCK), 0);
+}
+
+fn header(name: &[u8], size: u64, mtime: u64, typeflag: u8) -> std::io::Result<[u8; BLOCK]> {
+    // 大小字段只有11位八进制数字
+    if size >= 1 << 33 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "tar entries are limited to 8 GiB",
+        ));
+    }
+    let mut header = [0; BLOCK];
+    // 过长的名字已经写在LongLink条目中，这里只保留前100字节
+    let name = &name[..name.len().min(100)];
+    header[..name.len()].copy_from_slice(name);
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], mtime);
+    header[156] = typeflag;
+    header[257..265].copy_from_slice(b"ustar  \0");
+    // 校验和按校验和字段全为空格时计算
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    Ok(header)
+}
+
+// 以NUL结尾的定长八进制数字
+fn octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    field[..digits].copy_from_slice(format!("{:0width$o}", value, width = digits).as_bytes());
+    field[digits] = 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_dir;
+    use std::io::Read;
+
+    #[test]
+    fn long_names_round_trip() {
+        let dir = test_dir("tar_long_name");
+        let path = dir.join("out.tar");
+        let long_name = format!("{}/image.avif", "nested".repeat(20));
+        assert!(long_name.len() > 100);
+        let mtime = UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+
+        let archive = TarArchive::create(&path).unwrap();
+        archive.append("short.avif", b"short", Some(mtime)).unwrap();
+        archive
+            .append(&long_name, &[7; BLOCK + 1], Some(mtime))
+            .unwrap();
+        assert!(archive.contains(&long_name));
+        archive.finish().unwrap();
+
+        let mut tar = tar::Archive::new(File::open(&path).unwrap());
+        let entries: Vec<_> = tar
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().to_string_lossy().into_owned();
+                assert_eq!(entry.header().mtime().unwrap(), 1_700_000_000);
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                (name, data)
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("short.avif".to_string(), b"short".to_vec()),
+                (long_name, vec![7; BLOCK + 1]),
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn renamed_claims_keep_the_extension() {
+        let dir = test_dir("tar_claim");
+        let archive = TarArchive::create(&dir.join("out.tar")).unwrap();
+        assert_eq!(
+            archive
+                .claim("a/b.avif", CollisionPolicy::Rename)
+                .as_deref(),
+            Some("a/b.avif")
+        );
+        assert_eq!(
+            archive
+                .claim("a/b.avif", CollisionPolicy::Rename)
+                .as_deref(),
+            Some("a/b_1.avif")
+        );
+        assert_eq!(archive.claim("a/b.avif", CollisionPolicy::Skip), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
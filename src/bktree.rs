@@ -0,0 +1,243 @@
+use image_hasher::ImageHash;
+
+/// 以汉明距离为度量的BK树，用于快速查找相似哈希
+#[derive(Default)]
+pub struct BkTree {
+    // 所有节点保存在一个数组里，子节点通过下标引用
+    nodes: Vec<Node>,
+    removed: usize,
+}
+
+struct Node {
+    hash: ImageHash,
+    children: Vec<(u32, usize)>,
+    // 删除的节点仍保留在树中用于导航，只是不再匹配
+    removed: bool,
+}
+
+impl BkTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len() - self.removed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 按插入顺序遍历所有哈希
+    pub fn hashes(&self) -> impl Iterator<Item = &ImageHash> {
+        self.nodes.iter().filter(|n| !n.removed).map(|n| &n.hash)
+    }
+
+    /// 按插入顺序取出所有哈希
+    pub fn into_hashes(self) -> impl Iterator<Item = ImageHash> {
+        self.nodes
+            .into_iter()
+            .filter(|n| !n.removed)
+            .map(|n| n.hash)
+    }
+
+    /// 编号为`id`的哈希，已删除或不存在时返回`None`
+    pub fn get(&self, id: usize) -> Option<&ImageHash> {
+        self.nodes
+            .get(id)
+            .filter(|node| !node.removed)
+            .map(|node| &node.hash)
+    }
+
+    /// 删除编号为`id`的哈希，不存在或已删除时返回`false`
+    ///
+    /// 其他哈希的编号保持不变
+    pub fn remove(&mut self, id: usize) -> bool {
+        match self.nodes.get_mut(id) {
+            Some(node) if !node.removed => {
+                node.removed = true;
+                self.removed += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 插入哈希值并返回它的编号，已存在完全相同的哈希时返回已有的编号
+    ///
+    /// 编号是节点在树中的下标，删除其他哈希后也不会变化
+    pub fn insert(&mut self, hash: ImageHash) -> usize {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node::new(hash));
+            return 0;
+        }
+
+        let mut current = 0;
+        loop {
+            let dist = self.nodes[current].hash.dist(&hash);
+            if dist == 0 {
+                // 重新插入已删除的哈希时恢复原节点
+                if self.nodes[current].removed {
+                    self.nodes[current].removed = false;
+                    self.removed -= 1;
+                }
+                return current;
+            }
+            match self.nodes[current]
+                .children
+                .iter()
+                .find(|(d, _)| *d == dist)
+            {
+                Some(&(_, child)) => current = child,
+                None => {
+                    let index = self.nodes.len();
+                    self.nodes.push(Node::new(hash));
+                    self.nodes[current].children.push((dist, index));
+                    return index;
+                }
+            }
+        }
+    }
+
+    /// 查找任意一个与`hash`距离不超过`threshold`的哈希，找到第一个即返回它的编号和距离
+    pub fn query_within(&self, hash: &ImageHash, threshold: u32) -> Option<(usize, u32)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        // 用栈代替递归，避免树很深时栈溢出
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let dist = node.hash.dist(hash);
+            if dist <= threshold && !node.removed {
+                return Some((index, dist));
+            }
+            // 三角不等式：只有边距离在[dist - threshold, dist + threshold]内的子树才可能匹配
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| d.abs_diff(dist) <= threshold)
+                    .map(|&(_, child)| child),
+            );
+        }
+        None
+    }
+
+    /// 查找与`hash`最近的哈希的编号及其距离，树为空或所有哈希都已删除时返回`None`
+    pub fn nearest(&self, hash: &ImageHash) -> Option<(usize, u32)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut best = u32::MAX;
+        let mut best_index = 0;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let dist = node.hash.dist(hash);
+            if !node.removed && dist < best {
+                best = dist;
+                best_index = index;
+            }
+            if best == 0 {
+                break;
+            }
+            // 只有可能比当前最近距离更近的子树才需要继续查找
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| d.abs_diff(dist) < best)
+                    .map(|&(_, child)| child),
+            );
+        }
+        // 所有节点都已删除
+        (best != u32::MAX).then_some((best_index, best))
+    }
+}
+
+impl Node {
+    fn new(hash: ImageHash) -> Self {
+        Node {
+            hash,
+            children: Vec::new(),
+            removed: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 8字节的哈希，最低的`bits`位为1
+    fn hash(bits: u32) -> ImageHash {
+        let value = if bits == 64 {
+            u64::MAX
+        } else {
+            (1u64 << bits) - 1
+        };
+        ImageHash::from_bytes(&value.to_le_bytes()).unwrap()
+    }
+
+    #[test]
+    fn duplicate_insert_returns_existing_id() {
+        let mut tree = BkTree::new();
+        assert!(tree.is_empty());
+        let first = tree.insert(hash(0));
+        let second = tree.insert(hash(3));
+        assert_ne!(first, second);
+        assert_eq!(tree.insert(hash(3)), second);
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.get(second), Some(&hash(3)));
+        assert_eq!(
+            tree.hashes().cloned().collect::<Vec<_>>(),
+            [hash(0), hash(3)]
+        );
+    }
+
+    #[test]
+    fn query_within_includes_the_threshold() {
+        let mut tree = BkTree::new();
+        let id = tree.insert(hash(5));
+        tree.insert(hash(20));
+        assert_eq!(tree.query_within(&hash(8), 3), Some((id, 3)));
+        assert_eq!(tree.query_within(&hash(9), 3), None);
+        assert_eq!(tree.query_within(&hash(9), 4), Some((id, 4)));
+    }
+
+    #[test]
+    fn nearest_skips_removed_hashes() {
+        let mut tree = BkTree::new();
+        assert_eq!(tree.nearest(&hash(0)), None);
+        let ids: Vec<_> = [10, 2, 30].map(|bits| tree.insert(hash(bits))).into();
+        assert_eq!(tree.nearest(&hash(0)), Some((ids[1], 2)));
+        assert!(tree.remove(ids[1]));
+        assert_eq!(tree.nearest(&hash(0)), Some((ids[0], 10)));
+        assert_eq!(tree.query_within(&hash(2), 0), None);
+        assert!(tree.remove(ids[0]));
+        assert!(tree.remove(ids[2]));
+        assert!(tree.is_empty());
+        assert_eq!(tree.nearest(&hash(0)), None);
+        assert_eq!(tree.query_within(&hash(0), 64), None);
+    }
+
+    #[test]
+    fn reinsert_revives_removed_hash() {
+        let mut tree = BkTree::new();
+        let root = tree.insert(hash(0));
+        let id = tree.insert(hash(7));
+        assert!(tree.remove(id));
+        assert!(!tree.remove(id));
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree.get(id), None);
+        assert_eq!(tree.insert(hash(7)), id);
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree.get(id), Some(&hash(7)));
+        // 删除根节点后其他哈希仍可查找
+        assert!(tree.remove(root));
+        assert_eq!(tree.query_within(&hash(7), 0), Some((id, 0)));
+        assert_eq!(tree.insert(hash(0)), root);
+        assert_eq!(tree.into_hashes().count(), 2);
+    }
+}
@@ -0,0 +1,201 @@
+/// URL路径的最后一段，去掉查询参数和片段，用于判断格式和命名输出
+///
+/// 没有文件名，或文件名是`.`、`..`、含有`\`、`:`等不能出现在文件名中的字符时返回`None`，
+/// 这样的名字放进输出路径后可能指向输出目录之外
+pub fn url_file_name(url: &str) -> Option<&str> {
+    let end = url.find(['?', '#']).unwrap_or(url.len());
+    let path = &url[..end];
+    // 跳过协议和主机，只有主机时没有文件名
+    let path = match path.find("://") {
+        Some(i) => path[i + 3..].split_once('/').map_or("", |(_, path)| path),
+        None => path,
+    };
+    let name = path.rsplit('/').next()?;
+    let unsafe_name = name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains(['\\', ':', '*', '?', '"', '<', '>', '|'])
+        || name.chars().any(char::is_control);
+    (!unsafe_name).then_some(name)
+}
+
+/// 把URL下载到内存中，同时进行的请求数不超过上限
+///
+/// 需要启用`url`特性，否则[`Fetcher::new`]总是返回错误
+pub struct Fetcher {
+    #[cfg(feature = "url")]
+    client: reqwest::blocking::Client,
+    #[cfg(feature = "url")]
+    in_flight: std::sync::Arc<crate::Semaphore>,
+    // 没有启用特性时无法创建
+    #[cfg(not(feature = "url"))]
+    never: std::convert::Infallible,
+}
+
+impl Fetcher {
+    /// 最多同时进行`max_in_flight`个请求，跟随重定向
+    pub fn new(max_in_flight: usize) -> std::io::Result<Self> {
+        #[cfg(feature = "url")]
+        {
+            let client = reqwest::blocking::Client::builder()
+                .user_agent(concat!("convert_img/", env!("CARGO_PKG_VERSION")))
+                .build()
+                .map_err(std::io::Error::other)?;
+            Ok(Fetcher {
+                client,
+                in_flight: std::sync::Arc::new(crate::Semaphore::new(max_in_flight.max(1))),
+            })
+        }
+        #[cfg(not(feature = "url"))]
+        {
+            let _ = max_in_flight;
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "convert_img was built without the url feature",
+            ))
+        }
+    }
+
+    /// 下载`url`的全部内容，HTTP错误状态也视为失败
+    ///
+    /// 达到上限时阻塞到其他请求完成，读完响应后才归还许可
+    pub fn fetch(&self, url: &str) -> std::io::Result<Vec<u8>> {
+        #[cfg(feature = "url")]
+        {
+            use std::io::Read;
+            let _permit = self.in_flight.acquire();
+            // 日志中已经有URL，错误信息里不再重复
+            let error = |e: reqwest::Error| std::io::Error::other(e.without_url());
+            let mut response = self
+                .client
+                .get(url)
+                .send()
+                .and_then(|response| response.error_for_status())
+                .map_err(error)?;
+            let mut data = Vec::new();
+            response.read_to_end(&mut data)?;
+            Ok(data)
+        }
+        #[cfg(not(feature = "url"))]
+        {
+            let _ = url;
+            match self.never {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_file_name_ignores_query_and_host() {
+        assert_eq!(
+            url_file_name("https://example.com/a/cat.jpg?w=100#top"),
+            Some("cat.jpg")
+        );
+        assert_eq!(url_file_name("file:///tmp/dog.png"), Some("dog.png"));
+        assert_eq!(url_file_name("https://example.com/a/"), None);
+        assert_eq!(url_file_name("https://example.com"), None);
+    }
+
+    #[test]
+    fn url_file_name_rejects_unsafe_names() {
+        for url in [
+            "https://example.com/..",
+            "https://example.com/a/.",
+            "https://example.com/a\\..\\..\\evil.png",
+            "https://example.com/C:evil.png",
+            "https://example.com/a:b.png",
+            "https://example.com/c%0a.png\n",
+        ] {
+            assert_eq!(url_file_name(url), None, "{}", url);
+        }
+        // 编码后的分隔符只是普通字符
+        assert_eq!(
+            url_file_name("https://example.com/a%2F..%2Fb.png"),
+            Some("a%2F..%2Fb.png")
+        );
+    }
+
+    #[cfg(feature = "url")]
+    mod http {
+        use super::*;
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        // 在本机端口上应答请求，/missing返回404，其他路径返回路径本身。返回基础URL和同时处理的最大请求数
+        fn serve() -> (String, Arc<AtomicUsize>) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let base = format!("http://{}", listener.local_addr().unwrap());
+            let active = Arc::new(AtomicUsize::new(0));
+            let most = Arc::new(AtomicUsize::new(0));
+            let max_active = most.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let (active, most) = (active.clone(), most.clone());
+                    std::thread::spawn(move || {
+                        let mut reader = BufReader::new(stream.try_clone().unwrap());
+                        let mut request = String::new();
+                        reader.read_line(&mut request).unwrap();
+                        let path = request.split(' ').nth(1).unwrap().to_string();
+                        let mut line = String::new();
+                        while reader.read_line(&mut line).unwrap() > 2 {
+                            line.clear();
+                        }
+                        let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                        most.fetch_max(now, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(50));
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        let status = if path == "/missing" {
+                            "404 Not Found"
+                        } else {
+                            "200 OK"
+                        };
+                        let _ = write!(
+                            stream,
+                            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            status,
+                            path.len(),
+                            path
+                        );
+                    });
+                }
+            });
+            (base, max_active)
+        }
+
+        #[test]
+        fn fetches_into_memory_and_reports_http_errors() {
+            let (base, _) = serve();
+            let fetcher = Fetcher::new(2).unwrap();
+            assert_eq!(
+                fetcher.fetch(&format!("{}/a/cat.png", base)).unwrap(),
+                b"/a/cat.png"
+            );
+            let error = fetcher
+                .fetch(&format!("{}/missing", base))
+                .unwrap_err()
+                .to_string();
+            assert!(error.contains("404"), "{}", error);
+            assert!(!error.contains(&base), "{}", error);
+        }
+
+        #[test]
+        fn requests_in_flight_are_capped() {
+            let (base, max_active) = serve();
+            let fetcher = Fetcher::new(2).unwrap();
+            std::thread::scope(|scope| {
+                for i in 0..6 {
+                    let (fetcher, base) = (&fetcher, &base);
+                    scope.spawn(move || fetcher.fetch(&format!("{}/{}.png", base, i)).unwrap());
+                }
+            });
+            assert_eq!(max_active.load(Ordering::SeqCst), 2);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// 源目录中的忽略规则文件名，规则只作用于所在目录及其子目录
+pub const IGNORE_FILE_NAME: &str = ".convertignore";
+
+/// 一个`.convertignore`文件中的规则，语法是gitignore的子集：
+///
+/// - 空行和`#`开头的行被忽略
+/// - 不含`/`的规则匹配任意层级的文件名或目录名，含`/`的规则相对于规则文件所在目录
+/// - 以`/`结尾的规则只匹配目录，被忽略的目录不会进入，其中的文件也无法再用`!`包含
+/// - `!`开头的规则重新包含之前被排除的路径，同一文件中靠后的规则优先，子目录中的文件优先于上层
+pub struct IgnoreRules {
+    base: PathBuf,
+    globs: GlobSet,
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    negate: bool,
+    dir_only: bool,
+}
+
+impl IgnoreRules {
+    /// 读取`dir`中的规则文件，文件不存在时返回`None`
+    ///
+    /// 无法解析的规则打印警告后跳过
+    pub fn load(dir: &Path) -> Option<Self> {
+        let path = dir.join(IGNORE_FILE_NAME);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                eprintln!("Warning: failed to read {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        let mut builder = GlobSetBuilder::new();
+        let mut rules = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negate, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, line),
+            };
+            let (dir_only, pattern) = match pattern.strip_suffix('/') {
+                Some(pattern) => (true, pattern),
+                None => (false, pattern),
+            };
+            let pattern = match pattern.strip_prefix('/') {
+                Some(anchored) => anchored.to_string(),
+                None if pattern.contains('/') => pattern.to_string(),
+                None => format!("**/{}", pattern),
+            };
+            // 与gitignore相同，`*`不跨越目录
+            match GlobBuilder::new(&pattern).literal_separator(true).build() {
+                Ok(glob) => {
+                    builder.add(glob);
+                    rules.push(Rule { negate, dir_only });
+                }
+                Err(e) => eprintln!(
+                    "Warning: ignoring invalid pattern on line {} of {}: {}",
+                    number + 1,
+                    path.display(),
+                    e
+                ),
+            }
+        }
+        Some(IgnoreRules {
+            base: dir.to_path_buf(),
+            globs: builder.build().unwrap(),
+            rules,
+        })
+    }
+
+    // 最后一条匹配的规则决定结果，Some(true)为排除，Some(false)为重新包含，None为没有规则匹配
+    fn decide(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let relative = path.strip_prefix(&self.base).ok()?;
+        self.globs
+            .matches(relative)
+            .into_iter()
+            .rev()
+            .map(|i| &self.rules[i])
+            .find(|rule| is_dir || !rule.dir_only)
+            .map(|rule| !rule.negate)
+    }
+}
+
+/// 从最近的规则文件开始逐级向上检查，路径是否被排除
+pub fn is_ignored(chain: &[Arc<IgnoreRules>], path: &Path, is_dir: bool) -> bool {
+    chain
+        .iter()
+        .rev()
+        .find_map(|rules| rules.decide(path, is_dir))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_dir;
+
+    fn rules(dir: &Path, content: &str) -> Arc<IgnoreRules> {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join(IGNORE_FILE_NAME), content).unwrap();
+        Arc::new(IgnoreRules::load(dir).unwrap())
+    }
+
+    #[test]
+    fn negation_reincludes_later_matches() {
+        let dir = test_dir("ignore_negation");
+        let chain = [rules(&dir, "# 注释\n*.png\n!keep.png\n")];
+        assert!(is_ignored(&chain, &dir.join("a.png"), false));
+        assert!(is_ignored(&chain, &dir.join("sub/b.png"), false));
+        assert!(!is_ignored(&chain, &dir.join("sub/keep.png"), false));
+        assert!(!is_ignored(&chain, &dir.join("a.jpg"), false));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn leading_slash_anchors_to_the_rule_file() {
+        let dir = test_dir("ignore_anchor");
+        let chain = [rules(&dir, "/top.png\nsub/*.jpg\n")];
+        assert!(is_ignored(&chain, &dir.join("top.png"), false));
+        assert!(!is_ignored(&chain, &dir.join("sub/top.png"), false));
+        assert!(is_ignored(&chain, &dir.join("sub/a.jpg"), false));
+        // `*`不跨越目录
+        assert!(!is_ignored(&chain, &dir.join("sub/deep/a.jpg"), false));
+        assert!(!is_ignored(&chain, &dir.join("other/sub/a.jpg"), false));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn trailing_slash_matches_directories_only() {
+        let dir = test_dir("ignore_dir_only");
+        let chain = [rules(&dir, "cache/\n")];
+        assert!(is_ignored(&chain, &dir.join("cache"), true));
+        assert!(is_ignored(&chain, &dir.join("a/cache"), true));
+        assert!(!is_ignored(&chain, &dir.join("cache"), false));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn nested_rules_override_parent() {
+        let dir = test_dir("ignore_nested");
+        let sub = dir.join("sub");
+        let chain = [rules(&dir, "*.png\n"), rules(&sub, "!*.png\n*.jpg\n")];
+        assert!(is_ignored(&chain, &dir.join("a.png"), false));
+        assert!(!is_ignored(&chain, &sub.join("a.png"), false));
+        assert!(is_ignored(&chain, &sub.join("a.jpg"), false));
+        // 上层的规则只在子目录没有匹配时生效
+        assert!(is_ignored(&chain[..1], &sub.join("a.png"), false));
+        assert!(!is_ignored(&chain, &dir.join("a.jpg"), false));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
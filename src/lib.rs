@@ -0,0 +1,2060 @@
+mod archive;
+mod bktree;
+mod fetch;
+mod ignore;
+mod semaphore;
+mod store;
+
+pub use archive::TarArchive;
+pub use bktree::BkTree;
+pub use fetch::{Fetcher, url_file_name};
+pub use ignore::IGNORE_FILE_NAME;
+pub use semaphore::{Semaphore, SemaphorePermit};
+#[cfg(feature = "sqlite")]
+pub use store::SqliteStore;
+pub use store::{
+    FlatFileStore, HASH_FLUSH_INTERVAL, HashMeta, HashStore, Loaded, Match, ReferenceStore,
+    StoreInfo, StoreKind, load_reference_hashes, open_store, store_info,
+};
+
+use clap::ValueEnum;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::{IgnoreRules, is_ignored};
+use image::ImageError;
+use image::imageops::FilterType;
+use image::{AnimationDecoder, DynamicImage, ImageDecoder, ImageEncoder, ImageFormat};
+use image_hasher::{HashAlg, Hasher, HasherConfig, ImageHash};
+use indicatif::{HumanDuration, ProgressBar, ProgressState, ProgressStyle};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Seek, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::{
+    fmt,
+    fs::read_dir,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// 单张图片的转换参数
+#[derive(Clone, Copy)]
+pub struct ConvertOptions {
+    pub speed: u8,
+    pub quality: u8,
+    pub distance_threshold: u32,
+    pub output_format: OutputFormat,
+    /// 编码前将超出宽高限制的图片按比例缩小，哈希始终基于原图计算
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// 把源图片的EXIF和ICC配置文件写入输出
+    pub keep_metadata: bool,
+    /// 同时生成最长边不超过这个尺寸的缩略图
+    pub thumbnail: Option<u32>,
+    /// 输出超过这个字节数时降低质量重新编码，`quality`作为上限，只对AVIF有效
+    pub target_size: Option<u64>,
+}
+
+/// 转换结果
+pub struct Converted {
+    pub data: Vec<u8>,
+    /// 设置了[`ConvertOptions::thumbnail`]时的缩略图，不含元数据
+    pub thumbnail: Option<Vec<u8>>,
+    /// 实际使用的质量，设置了[`ConvertOptions::target_size`]时可能低于`quality`
+    pub quality: u8,
+}
+
+/// 从源图片读取的元数据
+#[derive(Default)]
+pub struct Metadata {
+    pub icc_profile: Option<Vec<u8>>,
+    /// 方向已经应用到像素上，这里的方向标记已重置
+    pub exif: Option<Vec<u8>>,
+}
+
+/// 输出格式
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum OutputFormat {
+    Avif,
+    /// 无损WebP，image目前只支持无损编码，quality和speed不生效
+    #[value(help = "Lossless WebP; --quality and --speed have no effect")]
+    Webp,
+    /// 无损PNG，quality和speed不生效
+    #[value(help = "Lossless PNG; --quality and --speed have no effect")]
+    Png,
+}
+
+impl OutputFormat {
+    /// 输出文件扩展名
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Avif => "avif",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Png => "png",
+        }
+    }
+
+    /// 每个通道能保存的最大位数，image的AVIF编码器只接受8位输入
+    pub fn max_bits_per_channel(&self) -> u16 {
+        match self {
+            OutputFormat::Avif | OutputFormat::Webp => 8,
+            OutputFormat::Png => 16,
+        }
+    }
+
+    /// 将解码后的图片编码为当前格式
+    ///
+    /// 格式不支持的元数据会被忽略，AVIF只能写入EXIF
+    pub fn encode(
+        &self,
+        img: &DynamicImage,
+        quality: u8,
+        speed: u8,
+        metadata: &Metadata,
+    ) -> Result<Vec<u8>, ImageError> {
+        let img = &encodable(img, *self);
+        let mut buffer = Vec::new();
+        match self {
+            OutputFormat::Avif => {
+                let mut encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                    &mut buffer,
+                    speed,
+                    quality,
+                );
+                set_metadata(&mut encoder, metadata);
+                img.write_with_encoder(encoder)?;
+            }
+            OutputFormat::Webp => {
+                let mut encoder = image::codecs::webp::WebPEncoder::new_lossless(&mut buffer);
+                set_metadata(&mut encoder, metadata);
+                encoder.write_image(
+                    img.as_bytes(),
+                    img.width(),
+                    img.height(),
+                    img.color().into(),
+                )?;
+            }
+            OutputFormat::Png => {
+                let mut encoder = image::codecs::png::PngEncoder::new(&mut buffer);
+                set_metadata(&mut encoder, metadata);
+                img.write_with_encoder(encoder)?;
+            }
+        }
+        Ok(buffer)
+    }
+}
+
+// 转为编码器支持的颜色类型。CMYK、调色板和1位图片解码时已展开为8位RGB或灰度，
+// 需要处理的只有浮点图片（EXR、HDR），AVIF和PNG编码器都不支持；WebP编码器只支持8位
+fn encodable(img: &DynamicImage, format: OutputFormat) -> Cow<'_, DynamicImage> {
+    match (format, img) {
+        (OutputFormat::Webp, DynamicImage::ImageRgba8(_)) => Cow::Borrowed(img),
+        (OutputFormat::Webp, _) => Cow::Owned(DynamicImage::ImageRgba8(img.to_rgba8())),
+        (OutputFormat::Avif, DynamicImage::ImageRgb32F(_)) => {
+            Cow::Owned(DynamicImage::ImageRgb8(img.to_rgb8()))
+        }
+        (OutputFormat::Avif, DynamicImage::ImageRgba32F(_)) => {
+            Cow::Owned(DynamicImage::ImageRgba8(img.to_rgba8()))
+        }
+        // 16位保留更多精度，超出[0, 1]的HDR值会被截断
+        (OutputFormat::Png, DynamicImage::ImageRgb32F(_)) => {
+            Cow::Owned(DynamicImage::ImageRgb16(img.to_rgb16()))
+        }
+        (OutputFormat::Png, DynamicImage::ImageRgba32F(_)) => {
+            Cow::Owned(DynamicImage::ImageRgba16(img.to_rgba16()))
+        }
+        _ => Cow::Borrowed(img),
+    }
+}
+
+fn set_metadata(encoder: &mut impl ImageEncoder, metadata: &Metadata) {
+    if let Some(icc_profile) = &metadata.icc_profile {
+        let _ = encoder.set_icc_profile(icc_profile.clone());
+    }
+    if let Some(exif) = &metadata.exif {
+        let _ = encoder.set_exif_metadata(exif.clone());
+    }
+}
+
+/// 感知哈希的尺寸，宽x高
+#[derive(Clone, Copy, PartialEq)]
+pub struct HashSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl HashSize {
+    /// 哈希的总位数
+    pub fn bits(&self) -> u32 {
+        self.width * self.height
+    }
+}
+
+impl std::str::FromStr for HashSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |v: &str| match v.trim().parse::<u32>() {
+            Ok(v) if v > 0 => Ok(v),
+            _ => Err(format!("expected WxH or a single dimension, got '{}'", s)),
+        };
+        let (width, height) = match s.split_once(['x', 'X']) {
+            Some((w, h)) => (parse(w)?, parse(h)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        Ok(HashSize { width, height })
+    }
+}
+
+impl fmt::Display for HashSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+// 清单文件，每行一个JSON对象
+pub const MANIFEST_FILE_NAME: &str = "manifest.jsonl";
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub source: String,
+    pub output: String,
+    pub hash: String,
+    pub source_bytes: u64,
+    pub output_bytes: u64,
+    /// 源图片的像素数，用于--keep-best比较，旧清单没有
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_pixels: Option<u64>,
+    /// 源文件的blake3哈希，只在启用--exact-dedup时记录
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+}
+
+/// 读取输出目录中的清单，不存在或无法解析的行会被忽略
+pub fn read_manifest(output_dir: &Path) -> Vec<ManifestEntry> {
+    std::fs::read_to_string(output_dir.join(MANIFEST_FILE_NAME))
+        .map(|manifest| {
+            manifest
+                .lines()
+                .filter_map(|l| serde_json::from_str(l).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// 默认识别的图片扩展名
+pub static IMAGE_FORMATS: [&str; 8] = ["jpg", "png", "jpeg", "webp", "bmp", "tiff", "tif", "gif"];
+
+/// 通常是本工具输出的格式，默认不作为输入，重新编码之前的输出时才加入
+pub static REENCODE_FORMATS: [&str; 1] = ["avif"];
+
+/// 查找图片时的选项
+pub struct FindOptions {
+    /// 识别的扩展名，需为小写
+    pub formats: Vec<String>,
+    /// 不进入的目录，需为规范化后的路径
+    pub skip_dirs: Vec<PathBuf>,
+    /// 是否进入指向目录的符号链接，指向文件的符号链接总会被读取
+    pub follow_symlinks: bool,
+    /// 是否按各级目录中的[`IGNORE_FILE_NAME`]文件排除路径
+    pub ignore_files: bool,
+}
+
+impl FindOptions {
+    /// 扩展名是否在`formats`中
+    pub fn matches_format(&self, path: &Path) -> bool {
+        self.formats.iter().any(|ext| {
+            path.extension()
+                .is_some_and(|e| e.to_ascii_lowercase() == ext.as_str())
+        })
+    }
+}
+
+impl Default for FindOptions {
+    fn default() -> Self {
+        FindOptions {
+            formats: IMAGE_FORMATS.map(String::from).to_vec(),
+            skip_dirs: Vec::new(),
+            follow_symlinks: false,
+            ignore_files: false,
+        }
+    }
+}
+
+/// 递归查找目录下所有支持的图片
+pub fn find_images<P: AsRef<Path>>(path: P) -> Vec<PathBuf> {
+    find_all_img_recusive(
+        path.as_ref(),
+        &FindOptions::default(),
+        &ProgressBar::hidden(),
+    )
+}
+
+/// 按`options`递归查找目录下的图片
+///
+/// 每找到一张图片`progress`加一，用于在扫描大目录时显示进度
+pub fn find_images_with<P: AsRef<Path>>(
+    path: P,
+    options: &FindOptions,
+    progress: &ProgressBar,
+) -> Vec<PathBuf> {
+    find_all_img_recusive(path.as_ref(), options, progress)
+}
+
+// 按层遍历目录，同一层的目录并行读取，不使用递归，目录很深时也不会栈溢出。
+// 跟随符号链接时记录进入过的规范化路径，链接成环或多次指向同一目录时只读取一次。
+// 每个目录带着从根目录到它的各级忽略规则，子目录共享上层的规则
+fn find_all_img_recusive(
+    path: &Path,
+    options: &FindOptions,
+    progress: &ProgressBar,
+) -> Vec<PathBuf> {
+    let mut images = Vec::new();
+    let visited = Mutex::new(HashSet::new());
+    let first_visit = |dir: &Path| {
+        !options.follow_symlinks
+            || dir
+                .canonicalize()
+                .is_ok_and(|p| visited.lock().unwrap().insert(p))
+    };
+    first_visit(path);
+    let mut dirs = vec![(path.to_path_buf(), Vec::new())];
+    while !dirs.is_empty() {
+        type Found = (Vec<(PathBuf, Vec<Arc<IgnoreRules>>)>, Vec<PathBuf>);
+        let found: Vec<Found> = dirs
+            .par_iter()
+            .map(|(dir, chain)| {
+                let mut subdirs = Vec::new();
+                let mut files = Vec::new();
+                let Ok(entries) = read_dir(dir) else {
+                    return (subdirs, files);
+                };
+                let mut chain = chain.clone();
+                if options.ignore_files
+                    && let Some(rules) = IgnoreRules::load(dir)
+                {
+                    chain.push(Arc::new(rules));
+                }
+                for entry in entries.flatten() {
+                    let path = entry.path();
+                    let is_symlink = entry.file_type().is_ok_and(|t| t.is_symlink());
+                    let is_dir = path.is_dir();
+                    if is_ignored(&chain, &path, is_dir) {
+                        continue;
+                    }
+                    if is_dir {
+                        if is_symlink && !options.follow_symlinks || !first_visit(&path) {
+                            continue;
+                        }
+                        if !options.skip_dirs.is_empty()
+                            && path
+                                .canonicalize()
+                                .is_ok_and(|p| options.skip_dirs.contains(&p))
+                        {
+                            continue;
+                        }
+                        subdirs.push((path, chain.clone()));
+                    } else if options.matches_format(&path) {
+                        files.push(path);
+                        progress.inc(1);
+                    }
+                }
+                (subdirs, files)
+            })
+            .collect();
+        dirs = Vec::new();
+        for (subdirs, files) in found {
+            dirs.extend(subdirs);
+            images.extend(files);
+        }
+    }
+    images
+}
+
+/// 按glob规则筛选源文件，排除规则优先于包含规则
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl PathFilter {
+    /// `include`为空时包含所有文件
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, globset::Error> {
+        let build = |patterns: &[String]| -> Result<GlobSet, globset::Error> {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(Glob::new(pattern)?);
+            }
+            builder.build()
+        };
+        Ok(PathFilter {
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(build(include)?)
+            },
+            exclude: build(exclude)?,
+        })
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        !self.exclude.is_match(path) && self.include.as_ref().is_none_or(|i| i.is_match(path))
+    }
+}
+
+/// 输出路径已被占用时的处理方式
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum CollisionPolicy {
+    /// 追加计数器另存为新文件
+    #[value(help = "Write to a new name with a counter appended")]
+    Rename,
+    /// 保留已有文件，跳过这张图片
+    #[value(help = "Keep the existing file and skip the image")]
+    Skip,
+    /// 覆盖已有文件
+    #[value(help = "Overwrite the existing file")]
+    Overwrite,
+    /// 报错，这张图片计为失败
+    #[value(help = "Report the image as failed")]
+    Error,
+}
+
+/// 按策略占用输出路径，返回None表示路径已被占用且策略不允许写入
+pub fn claim_output_path(path: &Path, policy: CollisionPolicy) -> std::io::Result<Option<PathBuf>> {
+    match policy {
+        CollisionPolicy::Rename => reserve_output_path(path).map(Some),
+        CollisionPolicy::Overwrite => Ok(Some(path.to_path_buf())),
+        CollisionPolicy::Skip | CollisionPolicy::Error => match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+        {
+            Ok(_) => Ok(Some(path.to_path_buf())),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(None),
+            Err(e) => Err(io_context(e, "create", path)),
+        },
+    }
+}
+
+/// 输出路径已被占用时追加计数器，用create_new占位保证并行写入时不会互相覆盖
+pub fn reserve_output_path(path: &Path) -> std::io::Result<PathBuf> {
+    let stem = path.file_stem().unwrap();
+    let ext = path.extension().unwrap();
+    let mut candidate = path.to_path_buf();
+    let mut counter = 1;
+    loop {
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+        {
+            Ok(_) => return Ok(candidate),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                // 用OsString拼接，非UTF-8文件名保持原样
+                let mut name = stem.to_os_string();
+                name.push(format!("_{}.", counter));
+                name.push(ext);
+                candidate = path.with_file_name(name);
+                counter += 1;
+            }
+            Err(e) => return Err(io_context(e, "create", &candidate)),
+        }
+    }
+}
+
+// 在错误信息前加上出错的操作和路径，错误类型不变
+pub(crate) fn io_context(e: std::io::Error, action: &str, path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        e.kind(),
+        format!("Failed to {} {}: {}", action, path.display(), e),
+    )
+}
+
+/// 移动文件，跨文件系统无法重命名时复制后删除原文件
+pub fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to)?;
+    std::fs::remove_file(from)
+}
+
+/// 源文件内容的blake3哈希，用于生成可复现的输出文件名，暂时性IO错误最多重试`retries`次
+pub fn content_hash<P: AsRef<Path>>(path: P, retries: u32) -> std::io::Result<String> {
+    retry_io(retries, || {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(std::fs::File::open(path.as_ref())?)?;
+        Ok(hasher.finalize().to_hex().to_string())
+    })
+}
+
+/// 与[`content_hash`]相同，计算已读入内存的数据的哈希
+pub fn content_hash_bytes(data: &[u8]) -> String {
+    blake3::hash(data).to_hex().to_string()
+}
+
+/// 写入中的临时文件的扩展名
+pub const PARTIAL_EXTENSION: &str = "part";
+
+/// 先写入同一目录下的`.part`临时文件并落盘，再重命名，中途退出时不会留下写了一半的输出
+///
+/// 遇到暂时性IO错误时整个写入过程最多重试`retries`次，见[`retry_io`]
+pub fn write_output(path: &Path, data: &[u8], retries: u32) -> std::io::Result<()> {
+    let part = write_partial(path, data, retries)?;
+    retry_io(retries, || std::fs::rename(&part, path)).inspect_err(|_| {
+        let _ = std::fs::remove_file(&part);
+    })
+}
+
+/// 只写入`path`旁的`.part`临时文件并落盘，返回临时文件的路径，由调用方决定何时重命名为`path`
+///
+/// 临时文件名带随机部分，覆盖同一个输出的并行写入不会共用临时文件。写入失败时删除临时文件，
+/// `path`本身不会被改动
+pub fn write_partial(path: &Path, data: &[u8], retries: u32) -> std::io::Result<PathBuf> {
+    let mut part = path.as_os_str().to_os_string();
+    part.push(format!(".{}.", uuid::Uuid::new_v4().simple()));
+    part.push(PARTIAL_EXTENSION);
+    let part = PathBuf::from(part);
+    retry_io(retries, || {
+        std::fs::File::create(&part).and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+    })
+    .inspect_err(|_| {
+        let _ = std::fs::remove_file(&part);
+    })?;
+    Ok(part)
+}
+
+/// 网络文件系统上常见的暂时性错误，重试通常能成功。文件不存在、权限不足等错误重试也没有用
+pub fn is_transient(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::Interrupted
+            | std::io::ErrorKind::TimedOut
+            | std::io::ErrorKind::WouldBlock
+    )
+}
+
+/// 执行`f`，遇到暂时性IO错误时最多重试`retries`次，等待时间从100ms开始每次翻倍
+pub fn retry_io<T>(retries: u32, mut f: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+    let mut backoff = Duration::from_millis(100);
+    for _ in 0..retries {
+        match f() {
+            Err(e) if is_transient(&e) => {
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+            result => return result,
+        }
+    }
+    f()
+}
+
+/// 在文件末尾追加一行，写入失败时截断回原来的长度
+///
+/// 磁盘写满时可能只写入了半行，不截断的话下一次追加会和它连成一行
+pub fn append_line(file: &mut std::fs::File, line: &str) -> std::io::Result<()> {
+    let len = file.metadata()?.len();
+    let mut buf = String::with_capacity(line.len() + 1);
+    buf.push_str(line);
+    buf.push('\n');
+    file.write_all(buf.as_bytes()).inspect_err(|_| {
+        let _ = file.set_len(len);
+    })
+}
+
+/// 磁盘空间或配额用尽，继续处理其他图片也只会失败
+pub fn is_storage_full(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded
+    )
+}
+
+/// 删除输出目录中上次运行被中断时留下的`.part`文件和[`claim_output_path`]的空占位文件，返回删除的数量
+pub fn remove_partial_outputs(output_dir: &Path) -> usize {
+    let mut formats = vec![PARTIAL_EXTENSION.to_string()];
+    formats.extend(
+        OutputFormat::value_variants()
+            .iter()
+            .map(|format| format.extension().to_string()),
+    );
+    let options = FindOptions {
+        formats,
+        ..FindOptions::default()
+    };
+    // 编码后的图片不会是空文件，空的输出只能是没有写入的占位文件
+    find_all_img_recusive(output_dir, &options, &ProgressBar::hidden())
+        .into_iter()
+        .filter(|path| {
+            path.extension().is_some_and(|ext| ext == PARTIAL_EXTENSION)
+                || std::fs::metadata(path).is_ok_and(|m| m.len() == 0)
+        })
+        .filter(|path| std::fs::remove_file(path).is_ok())
+        .count()
+}
+
+// 解码图片并按EXIF方向旋转，保证哈希和输出都与照片的显示方向一致
+fn decode_image(img_path: &Path, retries: u32) -> Result<(DynamicImage, Metadata), ImageError> {
+    // 先完整读入内存，读取失败可以重试，解码失败不会
+    let data = retry_io(retries, || std::fs::read(img_path))?;
+    decode_bytes(&data)
+}
+
+fn decode_bytes(data: &[u8]) -> Result<(DynamicImage, Metadata), ImageError> {
+    // 空文件猜不出格式，会报不直观的格式错误
+    if data.is_empty() {
+        return Err(ImageError::IoError(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "file is empty",
+        )));
+    }
+    if data.starts_with(&[0xFF, 0xD8]) && jpeg_truncated(data) {
+        return Err(ImageError::IoError(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "JPEG data is truncated",
+        )));
+    }
+    let mut decoder = image::ImageReader::new(std::io::Cursor::new(data))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut metadata = Metadata {
+        icc_profile: decoder.icc_profile()?,
+        exif: decoder.exif_metadata()?,
+    };
+    if let Some(exif) = &mut metadata.exif {
+        let _ = image::metadata::Orientation::remove_from_exif_chunk(exif);
+    }
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok((img, metadata))
+}
+
+// JPEG解码器遇到截断的数据会用灰色补齐而不报错，扫描数据之后没有结束标记时视为截断
+fn jpeg_truncated(data: &[u8]) -> bool {
+    // 按长度跳过扫描之前的段，APP段中内嵌的缩略图有自己的结束标记
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        match data[pos + 1] {
+            // 段之间的填充字节
+            0xFF => pos += 1,
+            0xDA => return !data[pos..].windows(2).any(|w| w == [0xFF, 0xD9]),
+            _ => pos += 2 + u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize,
+        }
+    }
+    true
+}
+
+/// 动图的处理方式
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum AnimatedPolicy {
+    /// 跳过动图
+    #[value(help = "Skip animated images")]
+    Skip,
+    /// 只用第一帧计算哈希并转换为静态图片
+    #[value(help = "Hash and convert only the first frame, as a still image")]
+    FirstFrame,
+    /// 报错，这张图片计为失败
+    #[value(help = "Report the image as failed")]
+    Error,
+}
+
+/// 源图片是否为动图，支持GIF、动态WebP和APNG
+///
+/// GIF没有记录帧数，需要解码到第二帧才能确定
+pub fn is_animated<P: AsRef<Path>>(img_path: P) -> Result<bool, ImageError> {
+    animated(image::ImageReader::open(img_path)?)
+}
+
+/// 与[`is_animated`]相同，检查已读入内存的图片
+pub fn is_animated_bytes(data: &[u8]) -> Result<bool, ImageError> {
+    animated(image::ImageReader::new(std::io::Cursor::new(data)))
+}
+
+fn animated<R: BufRead + Seek>(reader: image::ImageReader<R>) -> Result<bool, ImageError> {
+    let reader = reader.with_guessed_format()?;
+    let format = reader.format();
+    let reader = reader.into_inner();
+    Ok(match format {
+        Some(ImageFormat::Gif) => {
+            image::codecs::gif::GifDecoder::new(reader)?
+                .into_frames()
+                .take(2)
+                .count()
+                > 1
+        }
+        Some(ImageFormat::WebP) => image::codecs::webp::WebPDecoder::new(reader)?.has_animation(),
+        Some(ImageFormat::Png) => image::codecs::png::PngDecoder::new(reader)?.is_apng()?,
+        _ => false,
+    })
+}
+
+/// 只读取文件头，得到源图片每个通道的位数
+pub fn source_bits_per_channel<P: AsRef<Path>>(img_path: P) -> Result<u16, ImageError> {
+    bits_per_channel(image::ImageReader::open(img_path)?)
+}
+
+/// 与[`source_bits_per_channel`]相同，读取已读入内存的图片
+pub fn source_bits_per_channel_bytes(data: &[u8]) -> Result<u16, ImageError> {
+    bits_per_channel(image::ImageReader::new(std::io::Cursor::new(data)))
+}
+
+fn bits_per_channel<R: BufRead + Seek>(reader: image::ImageReader<R>) -> Result<u16, ImageError> {
+    let decoder = reader.with_guessed_format()?.into_decoder()?;
+    let color = decoder.color_type();
+    Ok(color.bits_per_pixel() / color.channel_count() as u16)
+}
+
+// 透明图片先叠加到白色背景上再计算哈希，完全透明处残留的颜色各不相同，不应影响结果。
+// 只用于哈希，输出仍保留透明通道
+fn flatten_alpha(img: &DynamicImage) -> DynamicImage {
+    let rgba = img.to_rgba8();
+    let rgb = image::RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        image::Rgb([blend(r), blend(g), blend(b)])
+    });
+    DynamicImage::ImageRgb8(rgb)
+}
+
+// 哈希前的预处理，透明图片叠加到白色背景，16位和浮点图片转为8位，`normalize`时再做灰度均衡。
+// 不需要处理时直接借用原图，转换时还要用到它
+fn prepare_for_hash(img: &DynamicImage, normalize: bool) -> Cow<'_, DynamicImage> {
+    let img = match img {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_) => Cow::Borrowed(img),
+        img if img.color().has_alpha() => Cow::Owned(flatten_alpha(img)),
+        img => Cow::Owned(DynamicImage::ImageRgb8(img.to_rgb8())),
+    };
+    if normalize {
+        Cow::Owned(equalize(&img))
+    } else {
+        img
+    }
+}
+
+// 转为灰度后做直方图均衡，同一幅作品在不同光照或扫描亮度下得到相近的哈希
+fn equalize(img: &DynamicImage) -> DynamicImage {
+    let mut luma = img.to_luma8();
+    let mut histogram = [0u64; 256];
+    for pixel in luma.pixels() {
+        histogram[pixel.0[0] as usize] += 1;
+    }
+    let mut cdf = [0u64; 256];
+    let mut sum = 0;
+    for (value, count) in histogram.iter().enumerate() {
+        sum += count;
+        cdf[value] = sum;
+    }
+    // 最暗的灰度映射到0，纯色图片保持不变
+    let min = cdf.iter().copied().find(|&c| c > 0).unwrap_or(0);
+    let total = sum;
+    if total == min {
+        return DynamicImage::ImageLuma8(luma);
+    }
+    for pixel in luma.pixels_mut() {
+        let c = cdf[pixel.0[0] as usize];
+        pixel.0[0] = ((c - min) * 255 / (total - min)) as u8;
+    }
+    DynamicImage::ImageLuma8(luma)
+}
+
+/// 判重时除原方向外还要比较的方向
+#[derive(Clone, Copy, Default)]
+pub struct Invariance {
+    /// 旋转90、180和270度
+    pub rotation: bool,
+    /// 水平翻转，与`rotation`同时开启时每个旋转方向都再翻转一次
+    pub mirror: bool,
+}
+
+// 按`invariance`计算各方向的哈希，只比较原方向时不复制图片
+fn orientation_hashes(
+    hasher: &Hasher,
+    invariance: Invariance,
+    img: &DynamicImage,
+) -> Vec<ImageHash> {
+    let mut images = vec![Cow::Borrowed(img)];
+    if invariance.rotation {
+        images.extend([img.rotate90(), img.rotate180(), img.rotate270()].map(Cow::Owned));
+    }
+    if invariance.mirror {
+        let mirrored: Vec<_> = images.iter().map(|img| Cow::Owned(img.fliph())).collect();
+        images.extend(mirrored);
+    }
+    images
+        .iter()
+        .map(|img| hasher.hash_image(img.as_ref()))
+        .collect()
+}
+
+/// 一张图片用于判重的哈希，设置了[`Context::invariance`]时包含各个方向的哈希
+///
+/// 任意一个方向与已存哈希相似即为重复，存储中只保存[`ImageHashes::canonical`]
+#[derive(Clone)]
+pub struct ImageHashes {
+    orientations: Vec<ImageHash>,
+}
+
+impl ImageHashes {
+    /// 各方向中base64最小的哈希，同一张图片无论以哪个方向出现都得到同一个值
+    pub fn canonical(&self) -> ImageHash {
+        self.orientations
+            .iter()
+            .min_by_key(|hash| hash.to_base64())
+            .unwrap()
+            .clone()
+    }
+}
+
+/// 只有一个方向，比如从清单中读取的哈希
+impl From<ImageHash> for ImageHashes {
+    fn from(hash: ImageHash) -> Self {
+        ImageHashes {
+            orientations: vec![hash],
+        }
+    }
+}
+
+/// 解码并按EXIF方向旋转后的源图片，计算哈希和转换共用同一份，不必解码两次
+pub struct Decoded {
+    img: DynamicImage,
+    metadata: Metadata,
+}
+
+// 二分查找不超过目标大小的最高质量，最多编码8次。最低质量仍超出时返回最低质量的结果
+fn encode_to_target(
+    img: &DynamicImage,
+    options: &ConvertOptions,
+    metadata: &Metadata,
+    target: u64,
+) -> Result<(Vec<u8>, u8), ImageError> {
+    let encode = |quality| {
+        options
+            .output_format
+            .encode(img, quality, options.speed, metadata)
+    };
+    let data = encode(options.quality)?;
+    if data.len() as u64 <= target {
+        return Ok((data, options.quality));
+    }
+    // 小于lo的质量都满足目标，不小于hi的都超出
+    let (mut lo, mut hi) = (0, options.quality);
+    let mut best = None;
+    let mut smallest = (data, options.quality);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        let data = encode(mid)?;
+        if data.len() as u64 <= target {
+            best = Some((data, mid));
+            lo = mid + 1;
+        } else {
+            smallest = (data, mid);
+            hi = mid;
+        }
+    }
+    Ok(best.unwrap_or(smallest))
+}
+
+/// 预览图每行最多的格数
+pub const CONTACT_SHEET_COLUMNS: usize = 4;
+
+// 超出尺寸限制时按比例缩小，未超出的图片原样返回
+fn fit_within(img: DynamicImage, max_width: Option<u32>, max_height: Option<u32>) -> DynamicImage {
+    let max_width = max_width.unwrap_or(u32::MAX).min(img.width());
+    let max_height = max_height.unwrap_or(u32::MAX).min(img.height());
+    if img.width() == max_width && img.height() == max_height {
+        return img;
+    }
+    img.resize(max_width, max_height, FilterType::Lanczos3)
+}
+
+/// 与已存哈希比较的结果
+pub enum HashDecision {
+    /// 没有相似图片，附带这张图片的规范哈希
+    Novel(ImageHash),
+    /// 与编号为`of`的已存记录相似，`distance`是两者的汉明距离
+    ///
+    /// 编号见[`Match::id`]，可以用[`Context::stored_hash`]取得对应的哈希
+    Duplicate { of: usize, distance: u32 },
+}
+
+/// [`Context::try_insert_hash`]的结果
+pub enum Inserted {
+    /// 哈希已写入存储，附带新记录的编号
+    Stored(usize),
+    /// 写锁内发现了相似的已存哈希，没有写入
+    Duplicate { of: usize, distance: u32 },
+}
+
+// 任意一个方向与已存哈希相似即为重复，否则返回规范哈希
+fn decide(hashes: &dyn HashStore, image: &ImageHashes, distance_threshold: u32) -> HashDecision {
+    match image
+        .orientations
+        .iter()
+        .find_map(|hash| hashes.contains_near(hash, distance_threshold))
+    {
+        Some(Match { id, distance }) => HashDecision::Duplicate { of: id, distance },
+        None => HashDecision::Novel(image.canonical()),
+    }
+}
+
+const HASHES_HEADER_PREFIX: &str = "#convert_img v1 ";
+
+/// hashes文件头，记录生成哈希时使用的算法和尺寸
+pub fn hashes_header(hash_alg: HashAlg, hash_size: HashSize) -> String {
+    format!(
+        "{}alg={:?} size={}",
+        HASHES_HEADER_PREFIX, hash_alg, hash_size
+    )
+}
+
+// 从文件头中读取字段，没有文件头的旧文件都是用DoubleGradient和64x64生成的
+fn header_field<'a>(content: &'a str, key: &str, default: &'a str) -> &'a str {
+    content
+        .lines()
+        .next()
+        .filter(|l| l.starts_with('#'))
+        .and_then(|l| {
+            l.split_whitespace()
+                .find_map(|f| f.strip_prefix(key)?.strip_prefix('='))
+        })
+        .unwrap_or(default)
+}
+
+// 检查hashes文件是否由相同版本、算法和尺寸生成
+fn check_hashes_header(
+    content: &str,
+    hash_alg: HashAlg,
+    hash_size: HashSize,
+) -> Result<(), String> {
+    let first_line = content.lines().next().unwrap_or_default();
+    if first_line.starts_with('#') && !first_line.starts_with(HASHES_HEADER_PREFIX) {
+        return Err(format!("has an unsupported header '{}'", first_line));
+    }
+    let file_alg = header_field(content, "alg", "DoubleGradient");
+    let file_size = header_field(content, "size", "64x64");
+    if file_alg != format!("{:?}", hash_alg) || file_size != hash_size.to_string() {
+        return Err(format!(
+            "was built with alg={} size={}, but alg={:?} size={} was requested",
+            file_alg, file_size, hash_alg, hash_size
+        ));
+    }
+    Ok(())
+}
+
+// 文件头不匹配时拒绝继续，除非指定了force
+fn validate_hashes_header(
+    hash_file_path: &Path,
+    content: &str,
+    hash_alg: HashAlg,
+    hash_size: HashSize,
+    force: bool,
+) -> std::io::Result<()> {
+    if let Err(reason) = check_hashes_header(content, hash_alg, hash_size) {
+        let message = format!(
+            "Hashes file {} {}. Distances between hashes built with different settings are meaningless",
+            hash_file_path.display(),
+            reason
+        );
+        if !force {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "{}; rebuild the hashes with matching settings or pass --force",
+                    message
+                ),
+            ));
+        }
+        eprintln!("Warning: {}", message);
+    }
+    Ok(())
+}
+
+/// 一次运行的哈希计算器、判重设置和哈希存储
+///
+/// 代替全局状态显式传给需要的函数，同一进程中可以先后或同时使用多个，互不影响
+pub struct Context {
+    hasher: Hasher,
+    hash_alg: HashAlg,
+    hash_size: HashSize,
+    /// 判重时除原方向外还要比较的方向
+    pub invariance: Invariance,
+    /// 计算哈希前是否先转为灰度并做直方图均衡
+    ///
+    /// 只影响用于哈希的副本，输出仍使用原图。开启后哈希值会变化，同一个存储必须始终使用相同的设置
+    pub normalize: bool,
+    /// 读取源图片遇到暂时性IO错误时的重试次数，见[`retry_io`]
+    pub io_retries: u32,
+    hashes: RwLock<Box<dyn HashStore>>,
+}
+
+impl Context {
+    /// 只比较原方向，不重试，哈希存储为空的内存存储，可以用[`Context::set_store`]替换
+    pub fn new(hash_alg: HashAlg, hash_size: HashSize) -> Self {
+        Context {
+            hasher: HasherConfig::new()
+                .hash_alg(hash_alg)
+                .hash_size(hash_size.width, hash_size.height)
+                .to_hasher(),
+            hash_alg,
+            hash_size,
+            invariance: Invariance::default(),
+            normalize: false,
+            io_retries: 0,
+            hashes: RwLock::new(Box::new(BkTree::new())),
+        }
+    }
+
+    /// 创建时使用的哈希算法，打开存储时用于检查文件头
+    pub fn hash_alg(&self) -> HashAlg {
+        self.hash_alg
+    }
+
+    /// 创建时使用的哈希尺寸
+    pub fn hash_size(&self) -> HashSize {
+        self.hash_size
+    }
+
+    /// 用[`open_store`]打开的存储代替当前的哈希存储
+    pub fn set_store(&mut self, store: Box<dyn HashStore>) {
+        *self.hashes.get_mut().unwrap() = store;
+    }
+
+    /// 解码图片并按EXIF方向旋转，读取遇到暂时性错误时按[`Context::io_retries`]重试
+    pub fn decode<P: AsRef<Path>>(&self, img_path: P) -> Result<Decoded, ImageError> {
+        let (img, metadata) = decode_image(img_path.as_ref(), self.io_retries)?;
+        Ok(Decoded { img, metadata })
+    }
+
+    /// 解码已读入内存的图片，如下载得到的数据
+    pub fn decode_bytes(&self, data: &[u8]) -> Result<Decoded, ImageError> {
+        let (img, metadata) = decode_bytes(data)?;
+        Ok(Decoded { img, metadata })
+    }
+
+    /// 解码图片并计算感知哈希，设置了[`Context::invariance`]时返回各方向中的规范哈希
+    pub fn hash_image<P: AsRef<Path>>(&self, img_path: P) -> Result<ImageHash, ImageError> {
+        Ok(self.hash_decoded(&self.decode(img_path)?).canonical())
+    }
+
+    /// 预处理后计算已解码图片的感知哈希，按[`Context::invariance`]包括各个方向
+    pub fn hash_decoded(&self, decoded: &Decoded) -> ImageHashes {
+        ImageHashes {
+            orientations: orientation_hashes(
+                &self.hasher,
+                self.invariance,
+                &prepare_for_hash(&decoded.img, self.normalize),
+            ),
+        }
+    }
+
+    /// 将图片转换为`options.output_format`指定的格式，缩略图从同一份解码结果缩小得到
+    pub fn convert_one<P: AsRef<Path>>(
+        &self,
+        img_path: P,
+        options: &ConvertOptions,
+    ) -> Result<Converted, ImageError> {
+        self.convert_decoded(self.decode(img_path)?, options)
+    }
+
+    /// 与[`Context::convert_one`]相同，但使用判重时已经解码的图片
+    pub fn convert_decoded(
+        &self,
+        decoded: Decoded,
+        options: &ConvertOptions,
+    ) -> Result<Converted, ImageError> {
+        let Decoded { img, metadata } = decoded;
+        let metadata = if options.keep_metadata {
+            metadata
+        } else {
+            Metadata::default()
+        };
+        let img = fit_within(img, options.max_width, options.max_height);
+        let format = options.output_format;
+        let (data, quality) = match options.target_size {
+            Some(target) => encode_to_target(&img, options, &metadata, target)?,
+            None => (
+                format.encode(&img, options.quality, options.speed, &metadata)?,
+                options.quality,
+            ),
+        };
+        let thumbnail = options
+            .thumbnail
+            .map(|size| {
+                // 不放大比缩略图还小的图片
+                let thumbnail = if img.width().max(img.height()) > size {
+                    img.resize(size, size, FilterType::Lanczos3)
+                } else {
+                    img.clone()
+                };
+                format.encode(
+                    &thumbnail,
+                    options.quality,
+                    options.speed,
+                    &Metadata::default(),
+                )
+            })
+            .transpose()?;
+        Ok(Converted {
+            data,
+            thumbnail,
+            quality,
+        })
+    }
+
+    /// 把图片缩放后按网格拼成一张预览图，用于人工检查一组重复图片
+    ///
+    /// 每格`cell`像素见方，每行最多[`CONTACT_SHEET_COLUMNS`]格。第一张图片是保留的，加绿框，其余加红框
+    pub fn contact_sheet(&self, paths: &[&Path], cell: u32) -> Result<image::RgbImage, ImageError> {
+        const BORDER: u32 = 4;
+        const GAP: u32 = 8;
+        let columns = paths.len().clamp(1, CONTACT_SHEET_COLUMNS) as u32;
+        let rows = paths.len().div_ceil(columns as usize) as u32;
+        let mut sheet = image::RgbImage::from_pixel(
+            columns * (cell + GAP) + GAP,
+            rows * (cell + GAP) + GAP,
+            image::Rgb([48, 48, 48]),
+        );
+        for (i, path) in paths.iter().enumerate() {
+            let (img, _) = decode_image(path, self.io_retries)?;
+            let img = if img.color().has_alpha() {
+                flatten_alpha(&img)
+            } else {
+                img
+            };
+            let inner = cell - 2 * BORDER;
+            let thumbnail = img.resize(inner, inner, FilterType::Triangle).into_rgb8();
+            let color = if i == 0 {
+                image::Rgb([0, 200, 0])
+            } else {
+                image::Rgb([220, 0, 0])
+            };
+            // 边框和图片在格子中居中
+            let (width, height) = (
+                thumbnail.width() + 2 * BORDER,
+                thumbnail.height() + 2 * BORDER,
+            );
+            let x = GAP + (i as u32 % columns) * (cell + GAP) + (cell - width) / 2;
+            let y = GAP + (i as u32 / columns) * (cell + GAP) + (cell - height) / 2;
+            for dy in 0..height {
+                for dx in 0..width {
+                    sheet.put_pixel(x + dx, y + dy, color);
+                }
+            }
+            image::imageops::overlay(
+                &mut sheet,
+                &thumbnail,
+                (x + BORDER) as i64,
+                (y + BORDER) as i64,
+            );
+        }
+        Ok(sheet)
+    }
+
+    /// 计算图片的哈希，与存储中已有的哈希对比
+    ///
+    /// 重复时返回匹配到的已存记录的编号和距离，外部可以用编号关联记录对应的文件
+    pub fn compare_hash<P: AsRef<Path>>(
+        &self,
+        img_path: P,
+        distance_threshold: u32,
+    ) -> Result<HashDecision, ImageError> {
+        let hashes = self.hash_decoded(&self.decode(img_path)?);
+        Ok(self.compare(&hashes, distance_threshold))
+    }
+
+    /// 与存储中已有的哈希对比已经算好的哈希
+    pub fn compare(&self, hashes: &ImageHashes, distance_threshold: u32) -> HashDecision {
+        decide(
+            self.hashes.read().unwrap().as_ref(),
+            hashes,
+            distance_threshold,
+        )
+    }
+
+    /// 任意方向与最相似的已存哈希的距离，存储为空时返回`None`
+    ///
+    /// 需要完整查找最近的哈希，比[`Context::compare`]慢，用于详细输出
+    pub fn nearest_distance(&self, hashes: &ImageHashes) -> Option<u32> {
+        let store = self.hashes.read().unwrap();
+        hashes
+            .orientations
+            .iter()
+            .filter_map(|hash| store.nearest(hash))
+            .map(|m| m.distance)
+            .min()
+    }
+
+    /// 编号为`id`的已存哈希，记录已被替换时返回`None`
+    pub fn stored_hash(&self, id: usize) -> Option<ImageHash> {
+        self.hashes.read().unwrap().get(id).cloned()
+    }
+
+    /// 与`hash`完全相同的已存记录的编号
+    pub fn find_hash(&self, hash: &ImageHash) -> Option<usize> {
+        self.hashes
+            .read()
+            .unwrap()
+            .contains_near(hash, 0)
+            .map(|m| m.id)
+    }
+
+    /// 在写锁内再次检查并记录新转换图片的规范哈希
+    ///
+    /// 并行处理时两张相似图片可能同时通过[`Context::compare`]，检查和插入在同一把锁内完成，
+    /// 与判重时一样比较所有方向。只有第一个调用者得到[`Inserted::Stored`]，同时哈希和`meta`写入存储，
+    /// 存储写入失败时返回错误
+    pub fn try_insert_hash(
+        &self,
+        hashes: &ImageHashes,
+        distance_threshold: u32,
+        meta: &HashMeta,
+    ) -> std::io::Result<Inserted> {
+        let mut store = self.hashes.write().unwrap();
+        match decide(store.as_ref(), hashes, distance_threshold) {
+            HashDecision::Novel(hash) => Ok(Inserted::Stored(store.insert(hash, meta)?)),
+            HashDecision::Duplicate { of, distance } => Ok(Inserted::Duplicate { of, distance }),
+        }
+    }
+
+    /// 用新图片的哈希替换之前保留的编号为`old`的记录，返回新记录的编号，保留更好的重复图片时使用
+    pub fn replace_hash(
+        &self,
+        old: usize,
+        hash: ImageHash,
+        meta: &HashMeta,
+    ) -> std::io::Result<usize> {
+        self.hashes.write().unwrap().replace(old, hash, meta)
+    }
+
+    /// 删除编号为`id`的记录，输出没有写入时撤销[`Context::try_insert_hash`]记录的哈希
+    pub fn remove_hash(&self, id: usize) -> std::io::Result<()> {
+        self.hashes.write().unwrap().remove(id)
+    }
+
+    /// 把存储缓冲的哈希写入文件，长时间运行时定期调用，进程被强制结束时最多丢失一个间隔内的记录
+    pub fn flush_hashes(&self) -> std::io::Result<()> {
+        self.hashes.write().unwrap().flush()
+    }
+
+    /// 确保新记录的哈希落盘
+    pub fn sync_hashes(&self) -> std::io::Result<()> {
+        self.hashes.write().unwrap().sync()
+    }
+}
+
+/// 扫描目录时的进度提示，显示已找到的图片数量
+pub fn init_spinner() -> ProgressBar {
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] Scanning: {pos} images found",
+        )
+        .unwrap(),
+    );
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb
+}
+
+/// 默认的进度条模板，`{eta}`是剩余时间，`{per_sec}`是每秒处理的图片数
+pub const PB_TEMPLATE: &str =
+    "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} {per_sec} ETA {eta} {msg}";
+
+/// 处理图片的进度条，`template`为`None`时使用[`PB_TEMPLATE`]
+///
+/// `template`必须是合法的indicatif模板，否则panic
+pub fn init_pb(len: usize, template: Option<&str>) -> ProgressBar {
+    let pb = ProgressBar::new(len as u64);
+    pb.set_style(pb_style(template).with_key(
+        "eta",
+        |state: &ProgressState, w: &mut dyn fmt::Write| {
+            write!(w, "{}", HumanDuration(state.eta())).unwrap()
+        },
+    ));
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb
+}
+
+/// 按已处理的字节数估算剩余时间的进度条，大图比缩略图耗时得多，按文件数估算并不准确
+///
+/// 进度仍按文件数显示，每处理完一张图片需要把它的大小加到`done_bytes`再调用`inc(1)`
+pub fn init_pb_weighted(
+    len: usize,
+    total_bytes: u64,
+    done_bytes: Arc<AtomicU64>,
+    template: Option<&str>,
+) -> ProgressBar {
+    let pb = ProgressBar::new(len as u64);
+    pb.set_style(pb_style(template).with_key(
+        "eta",
+        move |state: &ProgressState, w: &mut dyn fmt::Write| {
+            let done = done_bytes.load(Ordering::Relaxed);
+            if done == 0 {
+                write!(w, "-").unwrap();
+                return;
+            }
+            let remaining = total_bytes.saturating_sub(done) as f64 / done as f64;
+            let eta = state.elapsed().mul_f64(remaining);
+            write!(w, "{}", HumanDuration(eta)).unwrap()
+        },
+    ));
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb
+}
+
+fn pb_style(template: Option<&str>) -> ProgressStyle {
+    ProgressStyle::with_template(template.unwrap_or(PB_TEMPLATE))
+        .unwrap()
+        .with_key("per_sec", per_sec)
+        .progress_chars("#>-")
+}
+
+// 每秒处理的图片数，indicatif自带的格式保留4位小数
+fn per_sec(state: &ProgressState, w: &mut dyn fmt::Write) {
+    write!(w, "{:.1} img/s", state.per_sec()).unwrap()
+}
+
+/// 重建时移出的重复图片存放的子目录
+pub const DUPLICATES_DIR_NAME: &str = "duplicates";
+
+/// 缩略图存放的子目录，目录结构与输出目录相同
+pub const THUMBNAILS_DIR_NAME: &str = "thumbnails";
+
+// 输出目录中由本工具生成的附属文件，重建时即使扩展名匹配也不当作图片
+const SIDECAR_FILE_NAMES: &[&str] = &[
+    "hashes",
+    "hashes.db",
+    "hashes.db-journal",
+    "hashes.db-wal",
+    "hashes.db-shm",
+    MANIFEST_FILE_NAME,
+];
+
+fn is_sidecar(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| SIDECAR_FILE_NAMES.contains(&name))
+}
+
+/// 根据各输出目录中指定格式的图片重新生成`hash_file_path`处的哈希存储
+///
+/// 多个输出目录共用一个存储时，按给出的顺序一起去重，存储中的输出路径不再相对于输出目录。
+/// `dedup_threshold`不为`None`时，与已保留图片距离不超过阈值的图片会被移动到所在输出目录的
+/// `duplicates/`子目录，移动记录追加到`duplicates/moved.log`；`force`允许覆盖由其他设置生成的hashes文件
+pub fn rebuild_hashes(
+    context: &Context,
+    output_dirs: &[PathBuf],
+    hash_file_path: &Path,
+    output_format: OutputFormat,
+    dedup_threshold: Option<u32>,
+    force: bool,
+    store_kind: StoreKind,
+) -> std::io::Result<()> {
+    let mut store = open_store(
+        store_kind,
+        hash_file_path,
+        context.hash_alg(),
+        context.hash_size(),
+        force,
+    )?;
+    let hashes = Mutex::new(Vec::new());
+
+    // 递归查找，--preserve-structure和--shard生成的子目录也包括在内，之前移出的重复图片、缩略图和附属文件除外
+    let find_options = FindOptions {
+        formats: vec![output_format.extension().to_string()],
+        ..FindOptions::default()
+    };
+    let mut file_vec = Vec::new();
+    for output_dir in output_dirs {
+        if !output_dir.is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Output directory {} does not exist", output_dir.display()),
+            ));
+        }
+        let duplicates_dir = output_dir.join(DUPLICATES_DIR_NAME);
+        let thumbnails_dir = output_dir.join(THUMBNAILS_DIR_NAME);
+        let mut files = find_all_img_recusive(output_dir, &find_options, &ProgressBar::hidden());
+        files.retain(|path| {
+            !path.starts_with(&duplicates_dir)
+                && !path.starts_with(&thumbnails_dir)
+                && !is_sidecar(path)
+        });
+        files.sort();
+        file_vec.extend(files.into_iter().map(|path| (output_dir.as_path(), path)));
+    }
+
+    let pb = ProgressBar::new(file_vec.len() as u64);
+    pb.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} {per_sec} {msg}",
+        )
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn fmt::Write| {
+            write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
+        })
+        .with_key("per_sec", per_sec)
+        .progress_chars("#>-"),
+    );
+    pb.enable_steady_tick(Duration::from_millis(100));
+
+    // 损坏的文件只记录下来，不中断整个重建
+    let failed = AtomicUsize::new(0);
+    file_vec.par_iter().for_each(|(output_dir, path)| {
+        // 并行处理时显示的是最近开始的一张
+        pb.set_message(
+            path.strip_prefix(output_dir)
+                .unwrap_or(path)
+                .display()
+                .to_string(),
+        );
+        match context.hash_image(path) {
+            Ok(hash) => hashes.lock().unwrap().push((*output_dir, path, hash)),
+            Err(e) => {
+                pb.println(format!("Image {} error: {:?}", path.display(), e));
+                failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        pb.inc(1);
+    });
+    pb.finish_with_message("");
+
+    let mut hashes = hashes.into_inner().unwrap();
+    // 按输出目录和路径的顺序去重，保证每次重建保留的是同一张图片
+    let dir_index = |dir: &Path| output_dirs.iter().position(|d| d == dir);
+    hashes.sort_by(|a, b| (dir_index(a.0), a.1).cmp(&(dir_index(b.0), b.1)));
+    if let Some(threshold) = dedup_threshold {
+        let moved = move_duplicates(&mut hashes, threshold)?;
+        println!(
+            "Moved {} duplicates to {}",
+            moved,
+            output_dirs
+                .iter()
+                .map(|dir| dir.join(DUPLICATES_DIR_NAME).display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    let shared = output_dirs.len() > 1;
+    let records = hashes
+        .into_iter()
+        .map(|(output_dir, path, hash)| {
+            let output = if shared {
+                path.as_path()
+            } else {
+                path.strip_prefix(output_dir).unwrap()
+            };
+            let meta = HashMeta {
+                source: None,
+                output: Some(output.display().to_string()),
+                distance_threshold: dedup_threshold.unwrap_or(0),
+            };
+            (hash, meta)
+        })
+        .collect();
+    store
+        .replace_all(records)
+        .map_err(|e| io_context(e, "save hashes to", hash_file_path))?;
+
+    println!(
+        "Hashes have been rebuilt and saved to {}",
+        hash_file_path.display()
+    );
+    let failed = failed.into_inner();
+    if failed > 0 {
+        println!("{} of {} files could not be hashed", failed, file_vec.len());
+    }
+    Ok(())
+}
+
+/// 合并存储中彼此距离不超过`distance_threshold`的哈希，每组只保留最早写入的一个，返回删除的数量
+///
+/// 没有可合并的哈希时不重写存储
+pub fn compact_hashes(
+    store: &mut dyn HashStore,
+    distance_threshold: u32,
+) -> std::io::Result<usize> {
+    let records = store.records()?;
+    let total = records.len();
+    let mut kept = BkTree::new();
+    let records: Vec<_> = records
+        .into_iter()
+        .filter(|(hash, _)| {
+            if kept.query_within(hash, distance_threshold).is_some() {
+                return false;
+            }
+            kept.insert(hash.clone());
+            true
+        })
+        .collect();
+    let collapsed = total - records.len();
+    if collapsed > 0 {
+        store.replace_all(records)?;
+    }
+    Ok(collapsed)
+}
+
+/// 在`dir_b`中查找与`dir_a`中图片最相似的，距离不超过`threshold`时返回`(A中的图片, B中的图片, 距离)`，按A中的路径排序
+///
+/// 两个目录都递归查找，除默认格式外也包括AVIF，`dir_b`下的缩略图和移出的重复图片目录除外。
+/// 无法解码的图片直接跳过
+pub fn find_cross_duplicates(
+    context: &Context,
+    dir_a: &Path,
+    dir_b: &Path,
+    threshold: u32,
+) -> Vec<(PathBuf, PathBuf, u32)> {
+    let formats: Vec<String> = IMAGE_FORMATS
+        .iter()
+        .chain(&REENCODE_FORMATS)
+        .map(|f| f.to_string())
+        .collect();
+    let hash_all = |dir: &Path, skip_dirs: Vec<PathBuf>| {
+        let options = FindOptions {
+            formats: formats.clone(),
+            skip_dirs,
+            ..FindOptions::default()
+        };
+        hash_paths(
+            context,
+            find_images_with(dir, &options, &ProgressBar::hidden()),
+        )
+    };
+
+    let skip_dirs = [DUPLICATES_DIR_NAME, THUMBNAILS_DIR_NAME]
+        .iter()
+        .filter_map(|name| dir_b.join(name).canonicalize().ok())
+        .collect();
+    // 同一个哈希对应多张图片时取路径最靠前的
+    let mut index = BkTree::new();
+    let mut paths = HashMap::new();
+    for (path, hash) in hash_all(dir_b, skip_dirs) {
+        paths.entry(index.insert(hash)).or_insert(path);
+    }
+
+    hash_all(dir_a, Vec::new())
+        .into_iter()
+        .filter_map(|(path, hash)| {
+            let (matched, distance) = index
+                .nearest(&hash)
+                .filter(|&(_, distance)| distance <= threshold)?;
+            Some((path, paths[&matched].clone(), distance))
+        })
+        .collect()
+}
+
+/// 检查`paths`中的图片彼此之间是否有相似的，返回`(较早的图片, 较晚的图片, 距离)`，先后按路径排序
+///
+/// 每张图片只与排在它前面的图片中最相似的一张配对，距离超过`threshold`的不返回。
+/// 无法解码的图片直接跳过
+pub fn find_near_duplicates(
+    context: &Context,
+    paths: Vec<PathBuf>,
+    threshold: u32,
+) -> Vec<(PathBuf, PathBuf, u32)> {
+    let mut index = BkTree::new();
+    let mut earlier = HashMap::new();
+    let mut pairs = Vec::new();
+    for (path, hash) in hash_paths(context, paths) {
+        if let Some((matched, distance)) = index
+            .nearest(&hash)
+            .filter(|&(_, distance)| distance <= threshold)
+        {
+            let matched: &PathBuf = &earlier[&matched];
+            pairs.push((matched.clone(), path.clone(), distance));
+        }
+        earlier.entry(index.insert(hash)).or_insert(path);
+    }
+    pairs
+}
+
+// 并行计算哈希并按路径排序，无法解码的图片跳过
+fn hash_paths(context: &Context, paths: Vec<PathBuf>) -> Vec<(PathBuf, ImageHash)> {
+    let mut hashes: Vec<(PathBuf, ImageHash)> = paths
+        .into_par_iter()
+        .filter_map(|path| {
+            let hash = context.hash_image(&path).ok()?;
+            Some((path, hash))
+        })
+        .collect();
+    hashes.sort_by(|a, b| a.0.cmp(&b.0));
+    hashes
+}
+
+// 将与已保留图片相似的文件移到所在输出目录的duplicates目录，并从hashes中移除，返回移动的数量
+//
+// 出错时停止移动，已移动的文件已写入moved.log，仍保留在hashes中的是未处理的文件
+fn move_duplicates(
+    hashes: &mut Vec<(&Path, &PathBuf, ImageHash)>,
+    threshold: u32,
+) -> std::io::Result<usize> {
+    let mut kept = BkTree::new();
+    let mut logs: HashMap<PathBuf, std::fs::File> = HashMap::new();
+    let mut moved = 0;
+    let mut result = Ok(());
+    hashes.retain(|(output_dir, path, hash)| {
+        if result.is_err() {
+            return true;
+        }
+        if kept.query_within(hash, threshold).is_none() {
+            kept.insert(hash.clone());
+            return true;
+        }
+
+        let mut move_one = || -> std::io::Result<()> {
+            let duplicates_dir = output_dir.join(DUPLICATES_DIR_NAME);
+            let target = duplicates_dir.join(path.strip_prefix(output_dir).unwrap());
+            let parent = target.parent().unwrap();
+            std::fs::create_dir_all(parent).map_err(|e| io_context(e, "create", parent))?;
+            let target = reserve_output_path(&target)?;
+            std::fs::rename(path, &target).map_err(|e| io_context(e, "move", path))?;
+            let log_path = duplicates_dir.join("moved.log");
+            let log = match logs.entry(duplicates_dir) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(
+                    std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&log_path)
+                        .map_err(|e| io_context(e, "open", &log_path))?,
+                ),
+            };
+            // 每行记录原路径和新路径，用制表符分隔，方便恢复
+            writeln!(log, "{}\t{}", path.display(), target.display())
+                .map_err(|e| io_context(e, "write", &log_path))?;
+            println!("Moved {} -> {}", path.display(), target.display());
+            Ok(())
+        };
+        match move_one() {
+            Ok(()) => {
+                moved += 1;
+                false
+            }
+            Err(e) => {
+                result = Err(e);
+                true
+            }
+        }
+    });
+    result.map(|_| moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 每个测试使用单独的临时目录，避免并行运行时互相影响
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("convert_img_test_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn options(output_format: OutputFormat) -> ConvertOptions {
+        ConvertOptions {
+            speed: 10,
+            quality: 60,
+            distance_threshold: 0,
+            output_format,
+            max_width: None,
+            max_height: None,
+            keep_metadata: false,
+            thumbnail: None,
+            target_size: None,
+        }
+    }
+
+    fn context() -> Context {
+        Context::new(
+            HashAlg::DoubleGradient,
+            HashSize {
+                width: 8,
+                height: 8,
+            },
+        )
+    }
+
+    fn gradient(width: u32, height: u32) -> image::RgbImage {
+        image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([(x * 255 / width) as u8, (y * 255 / height) as u8, 128])
+        })
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    // 只有一个GPS IFD的小端TIFF，GPSLatitudeRef为N
+    fn gps_exif() -> Vec<u8> {
+        let mut exif = b"II*\0".to_vec();
+        exif.extend(8u32.to_le_bytes());
+        // IFD0：一个指向GPS IFD的条目
+        exif.extend(1u16.to_le_bytes());
+        exif.extend(0x8825u16.to_le_bytes());
+        exif.extend(4u16.to_le_bytes());
+        exif.extend(1u32.to_le_bytes());
+        exif.extend(26u32.to_le_bytes());
+        exif.extend(0u32.to_le_bytes());
+        // GPS IFD
+        exif.extend(1u16.to_le_bytes());
+        exif.extend(0x0001u16.to_le_bytes());
+        exif.extend(2u16.to_le_bytes());
+        exif.extend(2u32.to_le_bytes());
+        exif.extend(b"N\0\0\0");
+        exif.extend(0u32.to_le_bytes());
+        exif
+    }
+
+    #[test]
+    fn stripped_output_has_no_exif() {
+        let dir = test_dir("strip_metadata");
+        let source = dir.join("gps.jpg");
+        let mut jpeg = Vec::new();
+        let mut encoder = image::codecs::jpeg::JpegEncoder::new(&mut jpeg);
+        encoder.set_exif_metadata(gps_exif()).unwrap();
+        encoder
+            .write_image(
+                gradient(64, 48).as_raw(),
+                64,
+                48,
+                image::ExtendedColorType::Rgb8,
+            )
+            .unwrap();
+        std::fs::write(&source, jpeg).unwrap();
+        let context = context();
+
+        // 先确认保留元数据时EXIF确实会写入输出，否则下面的断言没有意义
+        let kept = context
+            .convert_one(
+                &source,
+                &ConvertOptions {
+                    keep_metadata: true,
+                    ..options(OutputFormat::Avif)
+                },
+            )
+            .unwrap();
+        assert!(contains(&kept.data, &gps_exif()[8..]));
+
+        let stripped = context
+            .convert_one(&source, &options(OutputFormat::Avif))
+            .unwrap();
+        assert!(!contains(&stripped.data, b"Exif"));
+        assert!(!contains(&stripped.data, b"II*\0"));
+        assert!(!contains(&stripped.data, &gps_exif()[8..]));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn empty_file_is_a_decode_error() {
+        let dir = test_dir("empty_file");
+        let source = dir.join("empty.jpg");
+        std::fs::write(&source, b"").unwrap();
+        assert!(decode_image(&source, 0).is_err());
+        assert!(context().hash_image(&source).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncated_jpeg_is_a_decode_error() {
+        let dir = test_dir("truncated_jpeg");
+        let source = dir.join("truncated.jpg");
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new(&mut jpeg)
+            .write_image(
+                gradient(256, 256).as_raw(),
+                256,
+                256,
+                image::ExtendedColorType::Rgb8,
+            )
+            .unwrap();
+        // 截断在熵编码数据中间
+        jpeg.truncate(jpeg.len() / 2);
+        std::fs::write(&source, jpeg).unwrap();
+        assert!(decode_image(&source, 0).is_err());
+        assert!(
+            context()
+                .convert_one(&source, &options(OutputFormat::Avif))
+                .is_err()
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    #[test]
+    fn cmyk_jpeg_converts_as_rgb() {
+        // 16x16的Adobe CMYK JPEG，四个象限颜色不同
+        let source = fixture("cmyk.jpg");
+        let (img, _) = decode_image(&source, 0).unwrap();
+        assert_eq!(img.color(), image::ColorType::Rgb8);
+        assert_ne!(
+            img.to_rgb8().get_pixel(0, 0),
+            img.to_rgb8().get_pixel(15, 15)
+        );
+        let context = context();
+        context.hash_image(&source).unwrap();
+
+        let png = context
+            .convert_one(&source, &options(OutputFormat::Png))
+            .unwrap();
+        let png = image::load_from_memory(&png.data).unwrap();
+        assert_eq!(png.color(), image::ColorType::Rgb8);
+        assert_eq!(png.to_rgb8(), img.to_rgb8());
+
+        let avif = context
+            .convert_one(&source, &options(OutputFormat::Avif))
+            .unwrap();
+        // AVIF解码后总是RGBA，没有透明度的源图片应完全不透明
+        let avif = image::load_from_memory(&avif.data).unwrap().to_rgba8();
+        assert_eq!(avif.dimensions(), (16, 16));
+        assert!(avif.pixels().all(|p| p[3] == 255));
+        assert_ne!(avif.get_pixel(0, 0), avif.get_pixel(15, 15));
+    }
+
+    #[test]
+    fn one_bit_png_converts_as_grayscale() {
+        // 16x16、位深为1的灰度PNG，左上角黑色，右下角白色
+        let source = fixture("onebit.png");
+        let (img, _) = decode_image(&source, 0).unwrap();
+        assert_eq!(img.color(), image::ColorType::L8);
+        let luma = img.to_luma8();
+        assert!(luma.pixels().all(|p| p[0] == 0 || p[0] == 255));
+        let context = context();
+        context.hash_image(&source).unwrap();
+
+        let png = context
+            .convert_one(&source, &options(OutputFormat::Png))
+            .unwrap();
+        let png = image::load_from_memory(&png.data).unwrap();
+        assert_eq!(png.color(), image::ColorType::L8);
+        assert_eq!(png.to_luma8(), luma);
+
+        let avif = context
+            .convert_one(&source, &options(OutputFormat::Avif))
+            .unwrap();
+        let avif = image::load_from_memory(&avif.data).unwrap().to_luma8();
+        assert!(avif.get_pixel(0, 0)[0] < 32);
+        assert!(avif.get_pixel(15, 15)[0] > 223);
+    }
+
+    fn write_png(path: &Path) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        gradient(8, 8).save(path).unwrap();
+    }
+
+    #[test]
+    fn output_dir_inside_source_is_skipped() {
+        let dir = test_dir("nested_output");
+        let source = dir.join("photos");
+        write_png(&source.join("a.png"));
+        write_png(&source.join("trip/b.png"));
+        // 之前转换时写入源目录下的输出目录，其中的文件不应再被当作源
+        write_png(&source.join("converted/c.png"));
+        write_png(&source.join("converted/deeper/d.png"));
+        let source = source.canonicalize().unwrap();
+        let find_options = FindOptions {
+            formats: vec!["png".to_string()],
+            skip_dirs: vec![source.join("converted")],
+            ..FindOptions::default()
+        };
+        let mut found = find_images_with(&source, &find_options, &ProgressBar::hidden());
+        found.sort();
+        assert_eq!(found, vec![source.join("a.png"), source.join("trip/b.png")]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn transparency_survives_conversion() {
+        let dir = test_dir("alpha");
+        let source = dir.join("logo.png");
+        // 左半边完全透明，右半边不透明
+        image::RgbaImage::from_fn(32, 32, |x, y| {
+            let alpha = if x < 16 { 0 } else { 255 };
+            image::Rgba([200, (y * 8) as u8, 40, alpha])
+        })
+        .save(&source)
+        .unwrap();
+        let context = context();
+        for format in [OutputFormat::Avif, OutputFormat::Png, OutputFormat::Webp] {
+            let converted = context.convert_one(&source, &options(format)).unwrap();
+            let output = image::load_from_memory(&converted.data).unwrap();
+            assert!(
+                output.color().has_alpha(),
+                "{:?} lost the alpha channel",
+                format
+            );
+            let output = output.to_rgba8();
+            for (x, y, pixel) in output.enumerate_pixels() {
+                // AVIF有损编码，边缘附近允许少量误差
+                if x < 14 {
+                    assert!(
+                        pixel[3] < 8,
+                        "{:?} pixel ({}, {}) is not transparent",
+                        format,
+                        x,
+                        y
+                    );
+                } else if x > 17 {
+                    assert!(
+                        pixel[3] > 247,
+                        "{:?} pixel ({}, {}) is not opaque",
+                        format,
+                        x,
+                        y
+                    );
+                }
+            }
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn deep_tree_is_walked_completely() {
+        let dir = test_dir("deep_tree");
+        // 每层一张图片，另有一个并列的分支，任何一层漏掉都会少一个文件
+        let mut expected = Vec::new();
+        let mut level = dir.clone();
+        for depth in 0..200 {
+            level.push("d");
+            expected.push(level.join(format!("{}.png", depth)));
+            if depth % 50 == 0 {
+                expected.push(level.join("side").join("s.png"));
+            }
+        }
+        for path in &expected {
+            write_png(path);
+        }
+        let mut found = find_images_with(&dir, &FindOptions::default(), &ProgressBar::hidden());
+        found.sort();
+        expected.sort();
+        assert_eq!(found, expected);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_file_names_are_found() {
+        use std::os::unix::ffi::OsStrExt;
+        let dir = test_dir("non_utf8");
+        let path = dir.join(std::ffi::OsStr::from_bytes(b"caf\xe9.png"));
+        write_png(&path);
+        let found = find_images_with(&dir, &FindOptions::default(), &ProgressBar::hidden());
+        assert_eq!(found, vec![path]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn contexts_in_one_process_are_independent() {
+        let dir = test_dir("two_contexts");
+        let source = dir.join("gradient.png");
+        gradient(64, 48).save(&source).unwrap();
+        let first = context();
+        let second = Context::new(
+            HashAlg::Mean,
+            HashSize {
+                width: 16,
+                height: 16,
+            },
+        );
+
+        // 两个上下文同时在不同线程中转换，各自的哈希设置不互相影响
+        std::thread::scope(|scope| {
+            for context in [&first, &second] {
+                let source = &source;
+                scope.spawn(move || {
+                    let converted = context
+                        .convert_one(source, &options(OutputFormat::Png))
+                        .unwrap();
+                    let output = image::load_from_memory(&converted.data).unwrap();
+                    assert_eq!(output.to_rgb8(), gradient(64, 48));
+                });
+            }
+        });
+        assert_ne!(
+            first.hash_image(&source).unwrap().as_bytes().len(),
+            second.hash_image(&source).unwrap().as_bytes().len()
+        );
+
+        // 记录到一个上下文的哈希对另一个不可见
+        let hashes = first.hash_decoded(&first.decode(&source).unwrap());
+        assert!(matches!(
+            first
+                .try_insert_hash(&hashes, 0, &HashMeta::default())
+                .unwrap(),
+            Inserted::Stored(_)
+        ));
+        assert!(matches!(
+            first.compare_hash(&source, 0).unwrap(),
+            HashDecision::Duplicate { distance: 0, .. }
+        ));
+        assert!(matches!(
+            second.compare_hash(&source, 0).unwrap(),
+            HashDecision::Novel(_)
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // 蓝底上1像素宽的红色笔画，类似截图中的小号彩色文字
+    fn colored_text(width: u32, height: u32) -> image::RgbImage {
+        image::RgbImage::from_fn(width, height, |x, y| {
+            if x % 4 == 1 || (y % 6 == 2 && x % 8 < 5) {
+                image::Rgb([220, 20, 20])
+            } else {
+                image::Rgb([20, 40, 200])
+            }
+        })
+    }
+
+    #[test]
+    fn avif_keeps_full_chroma_for_colored_text() {
+        let source = DynamicImage::ImageRgb8(colored_text(64, 48));
+        let avif = OutputFormat::Avif
+            .encode(&source, 90, 10, &Metadata::default())
+            .unwrap();
+
+        // av1C的第3个字节依次是tier、high_bitdepth、twelve_bit、monochrome、subsampling_x、subsampling_y
+        let at = avif.windows(4).position(|w| w == b"av1C").unwrap() + 4;
+        assert_eq!(avif[at + 1] >> 5, 1, "expected the 4:4:4 High profile");
+        assert_eq!(avif[at + 2] & 0b1100, 0, "chroma is subsampled");
+
+        // 4:2:0会把相邻的红色笔画和蓝色背景混成紫色，4:4:4下每个像素的颜色都应接近原图
+        let decoded = image::load_from_memory(&avif).unwrap().to_rgb8();
+        let worst = decoded
+            .pixels()
+            .zip(source.as_rgb8().unwrap().pixels())
+            .map(|(a, b)| (0..3).map(|c| a[c].abs_diff(b[c])).max().unwrap())
+            .max()
+            .unwrap();
+        assert!(worst < 60, "a pixel is off by {}", worst);
+    }
+
+    #[test]
+    fn interrupted_outputs_are_removed() {
+        let dir = test_dir("remove_partial");
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("a.avif.0190.part"), b"partial").unwrap();
+        // 中断时留下的空占位文件
+        std::fs::write(dir.join("nested").join("b.avif"), b"").unwrap();
+        std::fs::write(dir.join("c.avif"), b"image").unwrap();
+        std::fs::write(dir.join("notes.txt"), b"").unwrap();
+
+        assert_eq!(remove_partial_outputs(&dir), 2);
+        assert!(!dir.join("nested").join("b.avif").exists());
+        assert!(dir.join("c.avif").exists());
+        assert!(dir.join("notes.txt").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,253 +1,4208 @@
-use clap::Parser;
-use image_hasher::{HashAlg, Hasher, HasherConfig, ImageHash};
-use img2avif::img2avif;
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-use rayon::prelude::*;
-use std::io::Write;
-use std::sync::{Mutex, RwLock};
-use std::{
-    collections::HashSet,
-    fmt,
-    fs::{File, read_dir},
-    path::Path,
-    sync::OnceLock,
-    time::Duration,
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use convert_img::{
+    AnimatedPolicy, BkTree, CollisionPolicy, Context, ConvertOptions, Converted,
+    DUPLICATES_DIR_NAME, Decoded, Fetcher, FindOptions, HASH_FLUSH_INTERVAL, HashDecision,
+    HashMeta, HashSize, HashStore, IMAGE_FORMATS, ImageHashes, Inserted, Invariance,
+    MANIFEST_FILE_NAME, ManifestEntry, Metadata, OutputFormat, PathFilter, REENCODE_FORMATS,
+    ReferenceStore, Semaphore, StoreKind, THUMBNAILS_DIR_NAME, TarArchive, append_line,
+    claim_output_path, compact_hashes, content_hash, content_hash_bytes, find_images_with,
+    find_near_duplicates, init_pb, init_pb_weighted, init_spinner, is_animated, is_animated_bytes,
+    is_storage_full, load_reference_hashes, move_file, open_store, read_manifest, rebuild_hashes,
+    remove_partial_outputs, reserve_output_path, retry_io, source_bits_per_channel,
+    source_bits_per_channel_bytes, store_info, url_file_name, write_output, write_partial,
 };
+use image::ImageError;
+use image_hasher::HashAlg;
+use image_hasher::ImageHash;
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use notify::Watcher;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime};
 
 #[derive(Parser)]
-struct Args {
+#[clap(
+    args_conflicts_with_subcommands = true,
+    after_help = "Exit status:\n  \
+    0    every image was converted, skipped as a duplicate or already converted\n  \
+    1    some images could not be converted\n  \
+    2    invalid arguments or sources, no images to process, or writing failed\n  \
+    130  interrupted with Ctrl-C"
+)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Without a subcommand the arguments are those of `convert`.
+    #[clap(flatten)]
+    convert: ConvertArgs,
+}
+
+// 退出码，与Cli的after_help一致，参数错误时clap同样以2退出
+const EXIT_ERRORS: i32 = 1;
+const EXIT_FATAL: i32 = 2;
+const EXIT_INTERRUPTED: i32 = 130;
+
+// 无法继续运行的错误，如存储与设置不匹配或无法读取参数指定的文件
+fn exit_fatal(message: impl std::fmt::Display) -> ! {
+    eprintln!("Error: {}", message);
+    std::process::exit(EXIT_FATAL);
+}
+
+// 结果和汇总写入失败时只报告，不影响退出码。输出接到head等命令时管道可能提前关闭，不算错误
+fn check_output(written: std::io::Result<()>) {
+    if let Err(e) = written
+        && e.kind() != std::io::ErrorKind::BrokenPipe
+    {
+        eprintln!("Error: Failed to write output: {}", e);
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert and dedup source images into the output directory (the default).
+    Convert(Box<ConvertArgs>),
+    /// Rebuild the hash store in the output directory from the converted images.
+    Rebuild(RebuildArgs),
+    /// Print image counts and sizes of an output directory and its hash store.
+    Stats(StatsArgs),
+    /// Merge hashes within --distance-threshold of each other in a hash store, keeping the
+    /// first one recorded of each group.
+    Compact(CompactArgs),
+    /// Print the perceptual hash of each image, and their distance when given two.
+    Hash(HashImageArgs),
+}
+
+// 0到100之间的百分比
+fn parse_percent(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(pct) if (0.0..=100.0).contains(&pct) => Ok(pct),
+        Ok(_) => Err("must be between 0 and 100".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// 距今的时长，如7d，或RFC 3339时间
+fn parse_since(s: &str) -> Result<SystemTime, String> {
+    let s = s.trim();
+    if let Some(unit) = s.chars().last().filter(char::is_ascii_alphabetic)
+        && let Ok(n) = s[..s.len() - 1].parse::<u64>()
+    {
+        let unit_secs = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 7 * 86400,
+            _ => return Err(format!("unknown unit '{}', expected s, m, h, d or w", unit)),
+        };
+        return n
+            .checked_mul(unit_secs)
+            .and_then(|secs| SystemTime::now().checked_sub(Duration::from_secs(secs)))
+            .ok_or_else(|| "too far in the past".to_string());
+    }
+    parse_rfc3339(s).ok_or_else(|| {
+        "expected an age such as 7d or an RFC 3339 time such as 2024-05-01T08:00:00Z".to_string()
+    })
+}
+
+// 转换和重建都需要的哈希设置
+#[derive(clap::Args)]
+struct HashArgs {
+    /// Maximum Hamming distance at which two images are treated as duplicates.
+    /// Higher values mean more aggressive dedup. Defaults to 10% of the hash bits
+    /// (409 for the default 64x64 hash).
+    #[clap(short, long)]
+    distance_threshold: Option<u32>,
+
+    /// Distance threshold as a percentage of the hash bits instead of an absolute count, e.g.
+    /// 2.4, so dedup stays equally aggressive when --hash-size changes. Rounded to the
+    /// nearest bit.
+    #[clap(long, conflicts_with = "distance_threshold", value_parser = parse_percent)]
+    distance_pct: Option<f64>,
+
+    /// Perceptual hash algorithm. Hashes built with different algorithms are not comparable.
+    #[clap(long, value_enum, default_value = "doublegradient")]
+    hash_alg: HashAlgArg,
+
+    /// Hash size as WxH or a single square dimension. Must match the size the hashes file was built with.
+    #[clap(long, default_value = "64x64")]
+    hash_size: HashSize,
+
+    /// Hash store backend. The SQLite store also records source, output and time for each hash
+    /// and needs convert_img built with the `sqlite` feature; the memory store keeps hashes
+    /// for this run only.
+    #[clap(long, value_enum, default_value = "flat")]
+    store: StoreKind,
+
+    /// Proceed even if the hashes file was built with a different algorithm or hash size.
+    #[clap(long)]
+    force: bool,
+
+    /// Also compare the 90, 180 and 270 degree rotations of each image, so rotated copies
+    /// count as duplicates. Hashing takes about four times as long. The stored hash is the
+    /// same for every orientation, so keep using this flag with stores built with it.
+    #[clap(long)]
+    rotation_invariant: bool,
+
+    /// Also compare the mirrored image (and its rotations with --rotation-invariant).
+    #[clap(long)]
+    mirror_invariant: bool,
+
+    /// Hash a grayscale, histogram-equalized copy of each image, so scans or photos of the
+    /// same picture under different lighting match. Outputs keep the original pixels. This
+    /// changes every hash value, so use it consistently with the stores it builds.
+    #[clap(long)]
+    hash_normalize: bool,
+}
+
+impl HashArgs {
+    // 按--hash-alg和--hash-size创建哈希计算器，按--rotation-invariant和--mirror-invariant设置
+    // 判重比较的方向，按--hash-normalize设置哈希前的预处理
+    fn context(&self) -> Context {
+        let mut context = Context::new(self.hash_alg.into(), self.hash_size);
+        context.invariance = Invariance {
+            rotation: self.rotation_invariant,
+            mirror: self.mirror_invariant,
+        };
+        context.normalize = self.hash_normalize;
+        context
+    }
+
+    // 距离阈值默认为哈希位数的10%，超出位数时报错退出
+    fn distance_threshold(&self) -> u32 {
+        let bits = self.hash_size.bits();
+        let distance_threshold = match (self.distance_threshold, self.distance_pct) {
+            (Some(distance_threshold), _) => distance_threshold,
+            (None, Some(pct)) => (bits as f64 * pct / 100.0).round() as u32,
+            (None, None) => bits / 10,
+        };
+        if distance_threshold > bits {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!(
+                        "--distance-threshold {} exceeds the {} bits of a {} hash",
+                        distance_threshold, bits, self.hash_size
+                    ),
+                )
+                .exit();
+        }
+        distance_threshold
+    }
+}
+
+#[derive(clap::Args)]
+struct ConvertArgs {
+    /// TOML file with defaults for source-dir (a list), output-dir, output-format, quality,
+    /// speed, distance-threshold or distance-pct, hashes-file-path and threads, using the flag
+    /// names as keys.
+    /// Flags given on the command line win. [[override]] tables with a glob or extension key
+    /// set quality and/or speed for matching sources on top of those; glob rules beat
+    /// extension rules and longer globs beat shorter ones.
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    // 配置文件中按源路径覆盖的编码参数，按从不具体到具体的顺序排列
+    #[clap(skip)]
+    overrides: Vec<EncodeOverride>,
+
+    /// Source directory to scan, or a single image to convert. Can be given multiple times;
+    /// all sources share one dedup store.
     #[clap(short, long)]
-    source_dir: Option<String>,
+    source_dir: Vec<PathBuf>,
+
+    /// Read newline or NUL separated image paths from this file instead of scanning
+    /// directories. Missing files and unsupported extensions are reported and skipped.
+    #[clap(long, conflicts_with = "stdin")]
+    from_file: Option<PathBuf>,
+
+    /// Read newline or NUL separated image paths from standard input, like --from-file.
+    /// Works with `find -print0`.
+    #[clap(long)]
+    stdin: bool,
+
+    /// Fetch the newline separated image URLs in this file and convert them too. Each image
+    /// is downloaded into memory when it is processed, with no temporary files; logs, the
+    /// manifest and --json record the URL. Outputs named after the source use the last
+    /// segment of the URL path. Failed fetches are reported and skipped. Needs convert_img
+    /// built with the `url` feature.
+    #[clap(long, conflicts_with_all = ["dry_run", "find_duplicates"])]
+    url_list: Option<PathBuf>,
+
+    /// Maximum number of --url-list downloads in flight at once.
+    #[clap(long, default_value = "8", value_parser = clap::value_parser!(u64).range(1..))]
+    max_fetches: u64,
+
+    /// Mirror the source directory layout under the output directory, keeping file names.
+    #[clap(long)]
+    preserve_structure: bool,
+
+    /// Descend into symlinked directories. Each directory is scanned once, so symlink loops
+    /// are safe. Symlinked files are always read.
+    #[clap(long)]
+    follow_symlinks: bool,
+
+    /// Don't read .convertignore files. By default a .convertignore in a source directory or
+    /// any of its subdirectories excludes paths below it, using gitignore syntax: one glob
+    /// per line, a leading '/' anchors it to that directory, a trailing '/' matches only
+    /// directories and '!' re-includes a path.
+    #[clap(long)]
+    no_ignore_files: bool,
+
+    /// Comma separated list of file extensions to pick up from the source directory.
+    #[clap(long, value_delimiter = ',', default_values_t = IMAGE_FORMATS.map(String::from))]
+    formats: Vec<String>,
+
+    /// Also pick up AVIF files, to re-encode earlier outputs at another --quality, size or
+    /// format without keeping the originals. The output directory must differ from the
+    /// source directories.
+    #[clap(long)]
+    reencode: bool,
+
+    /// Only process source paths matching this glob, e.g. '*/2023/*'. Can be given multiple times.
+    #[clap(long)]
+    include: Vec<String>,
 
-    #[clap(short, long, default_value = "./hashes")]
-    hashes_file_path: String,
+    /// Skip source paths matching this glob, e.g. '*/thumbs/*'. Wins over --include.
+    #[clap(long)]
+    exclude: Vec<String>,
+
+    /// Only process sources modified at or after this time: either an age such as 30m, 12h,
+    /// 7d or 2w, or an RFC 3339 time such as 2024-05-01T08:00:00+02:00 (a bare date is taken
+    /// as midnight UTC). Files whose modification time can't be read are processed anyway.
+    /// With a shared --hashes-file-path this gives incremental runs over a growing archive.
+    #[clap(long, value_parser = parse_since)]
+    since: Option<SystemTime>,
+
+    /// Name outputs after the source file stem instead of a random UUID.
+    #[clap(long)]
+    keep_name: bool,
+
+    /// Build output file names from a template instead of a random UUID. Placeholders:
+    /// {uuid} (time-ordered v7), {uuid4} (random v4), {stem} (source file stem), {date}
+    /// (UTC YYYY-MM-DD of the conversion) and {index} (1, 2, ... in completion order). A '/'
+    /// creates subdirectories, e.g. "{date}/converted_{uuid}". The extension is appended.
+    /// With --preserve-structure only the file name is replaced.
+    #[clap(long, conflicts_with_all = ["keep_name", "name_by_hash"])]
+    name_template: Option<NameTemplate>,
+
+    /// What to do when an output named by --keep-name, --name-template or
+    /// --preserve-structure already exists. With --name-by-hash only overwrite applies: sources
+    /// whose output exists are processed again and replace it if they still pass dedup.
+    #[clap(long, value_enum, default_value_t = CollisionPolicy::Rename)]
+    on_collision: CollisionPolicy,
+
+    /// Name outputs after a blake3 hash of the source file, so reruns produce identical names.
+    /// Sources whose output already exists are skipped unless --on-collision overwrite is given.
+    #[clap(long, conflicts_with_all = ["keep_name", "preserve_structure"])]
+    name_by_hash: bool,
+
+    /// Spread outputs over this many levels of subdirectories, e.g. ab/cd/<uuid>.avif for 2,
+    /// so huge runs don't end up in one flat directory. Each level is two hex digits of a
+    /// blake3 hash of the output file name, which spreads the default time-ordered UUIDs
+    /// evenly too. With --name-template the levels go below the template's directories.
+    #[clap(long, value_name = "LEVELS", value_parser = clap::value_parser!(u8).range(1..=4), conflicts_with = "preserve_structure")]
+    shard: Option<u8>,
+
+    /// Skip byte-identical copies of already converted sources by their blake3 hash before
+    /// decoding them. Content hashes are kept in the manifest, so this also speeds up reruns.
+    #[clap(long)]
+    exact_dedup: bool,
+
+    /// When a near duplicate has a higher resolution than the image kept for it, or the same
+    /// resolution and a larger file, convert it and delete the previously kept output.
+    /// Only outputs recorded in the manifest can be replaced.
+    #[clap(long)]
+    keep_best: bool,
+
+    /// Where the hash store lives. Defaults to ./hashes, or ./hashes.db with --store sqlite.
+    /// Point several runs with different output directories at one store to dedup across
+    /// all of them.
+    #[clap(long, visible_alias = "hash-file")]
+    hashes_file_path: Option<PathBuf>,
+
+    /// Also skip images matching the hashes in this file, e.g. the hashes of a master
+    /// collection. Read only, new hashes are never added to it. Can be given multiple times.
+    #[clap(long)]
+    reference_hashes: Vec<PathBuf>,
 
     #[clap(short, long, default_value = "./output")]
-    output_dir: String,
+    output_dir: PathBuf,
+
+    /// Output image format. AVIF and WebP store 8 bits per channel, so 16-bit sources lose
+    /// precision; such images are reported. PNG keeps 16 bits. AVIF is always encoded with
+    /// full 4:4:4 chroma and never subsampled to 4:2:0, so text and line art keep sharp
+    /// colored edges; the encoder has no setting to change this.
+    #[clap(long, value_enum, default_value = "avif")]
+    output_format: OutputFormat,
+
+    /// Downscale wider images to this width before encoding, keeping the aspect ratio.
+    /// Dedup hashes are always computed from the original, full-size image.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_width: Option<u32>,
+
+    /// Downscale taller images to this height before encoding, keeping the aspect ratio.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_height: Option<u32>,
+
+    /// Skip images narrower than this many pixels, e.g. icons and UI sprites.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    min_width: Option<u32>,
+
+    /// Skip images shorter than this many pixels.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    min_height: Option<u32>,
+
+    /// How to handle animated GIF, WebP and PNG sources: skip them, hash and convert only
+    /// their first frame to a still image, or report them as errors.
+    #[clap(long, value_enum, default_value_t = AnimatedPolicy::FirstFrame)]
+    animated: AnimatedPolicy,
 
-    #[clap(short, long, default_value = "6")]
+    /// Also write a thumbnail whose longest edge is at most this many pixels, in the same
+    /// format, under thumbnails/ in the output directory with the same name. Duplicates get none.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    thumbnail: Option<u32>,
+
+    /// Copy EXIF (capture date, GPS, camera) and the ICC color profile into the output.
+    /// Stripped by default for privacy. AVIF output keeps EXIF only; the ICC profile is dropped.
+    #[clap(long)]
+    keep_metadata: bool,
+
+    /// Guarantee that no EXIF (including GPS location), XMP or ICC profile from the source
+    /// reaches the output or thumbnail. This is already the default; the flag states it
+    /// explicitly in scripts and refuses to run together with --keep-metadata.
+    #[clap(long, conflicts_with = "keep_metadata")]
+    strip_metadata: bool,
+
+    /// AVIF encoder speed, 1 (slowest, smallest) to 10 (fastest).
+    #[clap(long, default_value = "6", value_parser = clap::value_parser!(u8).range(1..=10))]
     speed: u8,
 
-    #[clap(short, long, default_value = "85")]
+    /// AVIF quality, 0 to 100. Chroma is always kept at full 4:4:4 resolution at any quality,
+    /// which can make files larger than a 4:2:0 encode at the same setting.
+    #[clap(short, long, default_value = "85", value_parser = clap::value_parser!(u8).range(0..=100))]
     quality: u8,
 
-    #[clap(short, long, default_value = "false")]
-    rebuild_hashes: bool,
+    // 命令行或配置文件中是否给出了quality，用于提示它不生效
+    #[clap(skip)]
+    quality_given: bool,
+
+    /// Lower the AVIF quality per image until the output is at most this many bytes, by
+    /// binary search starting from --quality. Each image is encoded up to 8 times, so this
+    /// is much slower. Images still over the target at quality 0 are written at quality 0.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    target_size: Option<u64>,
 
-    #[clap(short, long, default_value = "8")]
+    /// Require pixel-exact output for archival. The AVIF encoder (rav1e) cannot encode
+    /// losslessly, so this must be combined with --output-format webp or png.
+    #[clap(long)]
+    lossless: bool,
+
+    /// Worker threads. 0 uses every logical core.
+    #[clap(short, long, default_value = "0")]
     threads: usize,
-}
 
-static IMAGE_FORMATS: [&str; 3] = ["jpg", "png", "jpeg"];
+    /// Cap on how many images are encoded at once, independent of --threads. Each encode
+    /// holds the decoded image plus encoder buffers, which at low --speed can reach several
+    /// times the image size, so lowering this trades throughput for memory. Threads waiting
+    /// for a slot stay idle, holding only the image they decoded for hashing. Defaults to
+    /// --threads.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_concurrent_encodes: Option<u32>,
 
-static HASHER: OnceLock<Hasher> = OnceLock::new();
-static HASHES: OnceLock<RwLock<HashSet<ImageHash>>> = OnceLock::new();
+    /// Give up on an image whose hashing or conversion takes longer than this many seconds,
+    /// log it as an error and move on. The stuck work cannot be cancelled and keeps running
+    /// on a detached thread until it finishes; this only keeps it from stalling the batch.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    timeout_secs: Option<u64>,
 
-fn main() {
-    let args = Args::parse();
-
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(args.threads)
-        .build_global()
-        .unwrap();
-
-    HASHER
-        .set(
-            HasherConfig::new()
-                .hash_alg(HashAlg::DoubleGradient)
-                .hash_size(64, 64)
-                .to_hasher(),
-        )
-        .unwrap_or_else(|_| panic!("Failed to create hasher"));
+    /// Retry reading a source image or writing an output this many times when it fails with
+    /// a transient I/O error (interrupted, timed out or would block), as happens on NFS and
+    /// SMB mounts. Waits 100ms before the first retry and twice as long before each next one.
+    /// Missing files and decode errors are never retried.
+    #[clap(long, default_value = "0")]
+    io_retries: u32,
 
-    if args.rebuild_hashes {
-        rebuild_hashes(&args.output_dir);
-        return;
-    }
+    /// Only print errors and the final summary, no per-image messages.
+    #[clap(long, conflicts_with = "verbose")]
+    quiet: bool,
 
-    let images = find_all_img_recusive(args.source_dir.expect("Please provide a source directory"));
+    /// Also print hash values, the distance to the nearest stored hash, and per-image timing.
+    #[clap(short, long)]
+    verbose: bool,
 
-    let output_dir = Path::new(&args.output_dir);
-    if !output_dir.exists() {
-        std::fs::create_dir_all(output_dir).unwrap();
-    }
+    /// Hide the progress bar and print a plain status line every 10 seconds instead, for CI
+    /// logs. Enabled automatically when stderr, where the bar is drawn, is not a terminal.
+    #[clap(long)]
+    no_progress: bool,
 
-    HASHES
-        .set(init_hashes(&args.hashes_file_path))
-        .unwrap_or_else(|_| panic!("Failed to create hashes"));
+    /// Custom indicatif template for the progress bar, for example
+    /// "{pos}/{len} {wide_bar} {eta}". {eta} is estimated from the bytes processed.
+    #[clap(long, conflicts_with = "no_progress")]
+    progress_template: Option<String>,
 
-    let hash_file_path = Path::new(&args.hashes_file_path);
-    let hashes_file = Mutex::new(
-        std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(hash_file_path)
-            .unwrap(),
-    );
+    /// Skip sources that the manifest in the output directory records as converted,
+    /// as long as their output file still exists.
+    #[clap(long)]
+    resume: bool,
 
-    // 进度条
-    let pb = init_pb(images.len());
-    images.par_iter().for_each(|img_path| {
-        let img_path = Path::new(img_path);
+    /// Delete each source image once it has been converted and written.
+    /// Errors and duplicates are never touched. Asks for confirmation unless --yes is given.
+    /// The summary lists every removed source.
+    #[clap(long, conflicts_with = "move_source")]
+    remove_source: bool,
 
-        // 找到相同的图片
-        match compare_hash(img_path) {
-            Ok(Some(hash)) => {
-                pb.println(format!("Processing image: {}", img_path.display()));
-                // 转换图片格式
-                let file = File::open(img_path).unwrap();
-                let img = if let Ok(img) = img2avif(file, Some(args.speed), Some(args.quality)) {
-                    img
-                } else {
-                    pb.println(format!("Image {} conversion failed", img_path.display()));
-                    pb.inc(1);
-                    return;
-                };
+    /// Move each source image into this directory once it has been converted and written,
+    /// keeping its path relative to the source directory. Errors and duplicates stay in place.
+    /// The summary lists where each source was moved.
+    #[clap(long)]
+    move_source: Option<PathBuf>,
 
-                let output_path = output_dir.join(format!("{}.avif", uuid::Uuid::now_v7()));
-                std::fs::write(output_path, img).unwrap();
+    /// Do not ask for confirmation before deleting source images.
+    #[clap(long)]
+    yes: bool,
 
-                // 保存哈希值
-                writeln!(hashes_file.lock().unwrap(), "{}", hash.to_base64()).unwrap();
-                HASHES.get().unwrap().write().unwrap().insert(hash);
-                pb.inc(1);
-            }
-            Err(e) => {
-                pb.println(format!("Image {} error: {:?}", img_path.display(), e));
-                pb.inc(1);
-            }
-            _ => {
-                pb.println(format!("Image {} already exists", img_path.display()));
-                pb.inc(1);
-            }
+    /// Write converted images (and thumbnails) into this .tar file instead of loose files in
+    /// the output directory, using the same entry names. The output directory still keeps the
+    /// manifest; the manifest and a flat hash store are added to the archive at the end.
+    #[clap(long, conflicts_with_all = ["keep_best", "resume", "watch"])]
+    archive: Option<PathBuf>,
+
+    /// Give each output (and thumbnail) the modification time of its source image instead of
+    /// the time it was written, so sorting by date keeps the original order. Also applies
+    /// to entries written to --archive.
+    #[clap(long)]
+    preserve_mtime: bool,
+
+    /// Stop starting new conversions once outputs and thumbnails written in this run add
+    /// up to this many bytes. Images already being converted are still written, so the
+    /// total can overshoot by up to --threads images. Rerun with --resume to continue.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_output_bytes: Option<u64>,
+
+    /// Write one JSON object per processed image (source, status, output, hash, matched,
+    /// distance, bytes_in, bytes_out, error) to this file, or to stdout when no file is given.
+    /// With stdout the summary moves to stderr so stdout stays valid JSON Lines.
+    #[clap(long, num_args = 0..=1, default_missing_value = "-")]
+    json: Option<PathBuf>,
+
+    /// Write a CSV listing every skipped duplicate, the kept image it matched and their distance.
+    #[clap(long)]
+    report: Option<PathBuf>,
+
+    /// Hash and compare images, report what would happen, but write nothing.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// Convert nothing: hash the source images, group those within --distance-threshold of
+    /// each other and write every group of two or more to this CSV file, with file sizes and
+    /// dimensions to help pick which to keep. The output directory and hash store are untouched.
+    #[clap(long, conflicts_with_all = ["dry_run", "archive", "watch", "report", "json"])]
+    find_duplicates: Option<PathBuf>,
+
+    /// With --find-duplicates, also save a preview of each duplicate set to this directory as
+    /// group-0001.png and so on, numbered like the CSV. Thumbnails of the set are laid out side
+    /// by side: the image a conversion would keep has a green border, the ones it would skip
+    /// a red one.
+    #[clap(long, requires = "find_duplicates")]
+    contact_sheet: Option<PathBuf>,
+
+    /// Print a histogram of each image's distance to its nearest stored hash at the end,
+    /// to help pick --distance-threshold. Works with --dry-run.
+    #[clap(long)]
+    histogram: bool,
+
+    /// After the run, hash the outputs written by it and warn about any two within
+    /// --distance-threshold of each other, which dedup should have prevented. Outputs are
+    /// lossy, so their distances differ slightly from the sources'. Exits with 1 if any are found.
+    #[clap(long, conflicts_with = "archive")]
+    verify_dedup: bool,
+
+    /// Print where the time went at the end: wall time, directory discovery, and the time
+    /// images spent hashing, waiting for an encode slot, encoding and writing, summed over
+    /// all threads. Hashing and encoding each include decoding the source.
+    #[clap(long)]
+    timings: bool,
+
+    /// Process only this many randomly chosen images out of those found, e.g. to check
+    /// --quality and --speed on a representative subset first. Dedup still applies.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "watch")]
+    sample: Option<u64>,
+
+    /// Seed for --sample, to pick the same subset again. Random by default.
+    #[clap(long, requires = "sample")]
+    seed: Option<u64>,
+
+    /// Process at most this many images: the first ones found in path order, after filtering
+    /// and --sample. Unlike --sample the same images are picked every time, which keeps smoke
+    /// tests of a config, e.g. with --dry-run, short and repeatable.
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "watch")]
+    max_images: Option<u64>,
+
+    /// After the initial pass keep watching the source directories and convert images as they
+    /// appear, until Ctrl-C. The directories are rescanned only after the file system reports a
+    /// change, and a new file is processed once its size and modification time are unchanged
+    /// between two scans, so files still being written are not read. Falls back to scanning
+    /// periodically where file system notifications are unavailable.
+    #[clap(long, conflicts_with_all = ["dry_run", "benchmark"])]
+    watch: bool,
+
+    /// Minimum seconds between scans in --watch mode, and so how long a new file must stay
+    /// unchanged before it is converted.
+    #[clap(long, default_value = "2", value_parser = clap::value_parser!(u64).range(1..))]
+    watch_interval: u64,
+
+    /// Measure hashing and encoding throughput on generated sample images with the current
+    /// --hash-alg, --hash-size, --output-format, --quality and --speed, then exit.
+    #[clap(long)]
+    benchmark: bool,
+
+    #[clap(flatten)]
+    hash: HashArgs,
+}
+
+// --config文件中的默认值，键名与命令行参数相同
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    source_dir: Option<Vec<PathBuf>>,
+    output_dir: Option<PathBuf>,
+    output_format: Option<String>,
+    quality: Option<u8>,
+    speed: Option<u8>,
+    distance_threshold: Option<u32>,
+    distance_pct: Option<f64>,
+    hashes_file_path: Option<PathBuf>,
+    threads: Option<usize>,
+    #[serde(rename = "override", default)]
+    overrides: Vec<OverrideConfig>,
+}
+
+// 配置文件中的[[override]]，glob和extension必须且只能给出一个
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct OverrideConfig {
+    glob: Option<String>,
+    extension: Option<String>,
+    quality: Option<u8>,
+    speed: Option<u8>,
+}
+
+// 源文件的匹配方式
+enum SourceMatcher {
+    Glob(globset::GlobMatcher),
+    Extension(String),
+}
+
+// 解析后的[[override]]
+struct EncodeOverride {
+    matcher: SourceMatcher,
+    quality: Option<u8>,
+    speed: Option<u8>,
+}
+
+impl EncodeOverride {
+    fn matches(&self, path: &Path) -> bool {
+        match &self.matcher {
+            SourceMatcher::Glob(glob) => glob.is_match(path),
+            SourceMatcher::Extension(ext) => path
+                .extension()
+                .is_some_and(|e| e.to_ascii_lowercase() == ext.as_str()),
         }
-    });
+    }
+
+    // 规则越具体值越大：glob高于扩展名，glob越长越具体
+    fn specificity(&self) -> (bool, usize) {
+        match &self.matcher {
+            SourceMatcher::Glob(glob) => (true, glob.glob().glob().len()),
+            SourceMatcher::Extension(_) => (false, 0),
+        }
+    }
+}
 
-    pb.finish_with_message("Processing complete");
+// 依次应用匹配的规则，更具体的规则覆盖它设置的参数
+fn resolve_overrides(
+    overrides: &[EncodeOverride],
+    path: &Path,
+    options: ConvertOptions,
+) -> ConvertOptions {
+    overrides
+        .iter()
+        .filter(|rule| rule.matches(path))
+        .fold(options, |options, rule| ConvertOptions {
+            quality: rule.quality.unwrap_or(options.quality),
+            speed: rule.speed.unwrap_or(options.speed),
+            ..options
+        })
 }
 
-fn find_all_img_recusive<P: AsRef<Path>>(path: P) -> Vec<String> {
-    let mut images = Vec::new();
-    if let Ok(entries) = read_dir(path) {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if path.is_dir() {
-                images.extend(find_all_img_recusive(path));
-            } else if IMAGE_FORMATS.iter().any(|&ext| {
-                path.extension()
-                    .is_some_and(|e| e.to_ascii_lowercase() == ext)
-            }) {
-                if let Some(path_str) = path.to_str() {
-                    images.push(path_str.to_string());
+impl ConvertArgs {
+    // 用配置文件补全命令行上没有给出的参数，文件无法读取或内容无效时报错退出
+    fn apply_config(&mut self, matches: &ArgMatches) {
+        self.quality_given = matches.value_source("quality") == Some(ValueSource::CommandLine);
+        let Some(path) = &self.config else {
+            return;
+        };
+        let invalid = |message: String| -> ! {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("Invalid config {}: {}", path.display(), message),
+                )
+                .exit()
+        };
+        let content = std::fs::read_to_string(path).unwrap_or_else(|e| invalid(e.to_string()));
+        let config: Config = toml::from_str(&content).unwrap_or_else(|e| invalid(e.to_string()));
+        let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+
+        if let Some(source_dir) = config.source_dir
+            && unset("source_dir")
+        {
+            self.source_dir = source_dir;
+        }
+        if let Some(output_dir) = config.output_dir
+            && unset("output_dir")
+        {
+            self.output_dir = output_dir;
+        }
+        if let Some(format) = config.output_format
+            && unset("output_format")
+        {
+            self.output_format = OutputFormat::from_str(&format, true)
+                .unwrap_or_else(|_| invalid(format!("unknown output-format '{}'", format)));
+        }
+        if let Some(quality) = config.quality
+            && unset("quality")
+        {
+            if quality > 100 {
+                invalid(format!("quality {} is not in 0..=100", quality));
+            }
+            self.quality = quality;
+            self.quality_given = true;
+        }
+        if let Some(speed) = config.speed
+            && unset("speed")
+        {
+            if !(1..=10).contains(&speed) {
+                invalid(format!("speed {} is not in 1..=10", speed));
+            }
+            self.speed = speed;
+        }
+        // 两种写法互斥，命令行上给出任意一种时都不再使用配置文件中的阈值
+        if config.distance_threshold.is_some() && config.distance_pct.is_some() {
+            invalid("distance-threshold and distance-pct cannot both be set".to_string());
+        }
+        if unset("distance_threshold") && unset("distance_pct") {
+            if let Some(distance_threshold) = config.distance_threshold {
+                self.hash.distance_threshold = Some(distance_threshold);
+            }
+            if let Some(pct) = config.distance_pct {
+                if !(0.0..=100.0).contains(&pct) {
+                    invalid(format!("distance-pct {} is not in 0..=100", pct));
                 }
+                self.hash.distance_pct = Some(pct);
+            }
+        }
+        if let Some(hashes_file_path) = config.hashes_file_path
+            && unset("hashes_file_path")
+        {
+            self.hashes_file_path = Some(hashes_file_path);
+        }
+        if let Some(threads) = config.threads
+            && unset("threads")
+        {
+            self.threads = threads;
+        }
+        for rule in config.overrides {
+            let matcher = match (rule.glob, rule.extension) {
+                (Some(glob), None) => SourceMatcher::Glob(
+                    globset::Glob::new(&glob)
+                        .unwrap_or_else(|e| invalid(e.to_string()))
+                        .compile_matcher(),
+                ),
+                (None, Some(ext)) => SourceMatcher::Extension(
+                    ext.trim().trim_start_matches('.').to_ascii_lowercase(),
+                ),
+                _ => invalid("each [[override]] needs exactly one of glob or extension".into()),
+            };
+            if rule.quality.is_some_and(|quality| quality > 100) {
+                invalid(format!(
+                    "override quality {} is not in 0..=100",
+                    rule.quality.unwrap()
+                ));
             }
+            if rule.speed.is_some_and(|speed| !(1..=10).contains(&speed)) {
+                invalid(format!(
+                    "override speed {} is not in 1..=10",
+                    rule.speed.unwrap()
+                ));
+            }
+            self.overrides.push(EncodeOverride {
+                matcher,
+                quality: rule.quality,
+                speed: rule.speed,
+            });
         }
+        // 稳定排序，同样具体的规则中文件里靠后的优先
+        self.overrides.sort_by_key(EncodeOverride::specificity);
     }
-    images
 }
 
-// 获取目标文件夹hashes文件内保存的哈希值，然后与传入的Hash值进行对比
-fn compare_hash<P: AsRef<Path>>(
-    img_path: P,
-) -> Result<Option<ImageHash>, image::error::ImageError> {
-    let img = image::ImageReader::open(&img_path)?
-        .with_guessed_format()?
-        .decode()?;
-    let hasher = HASHER.get().unwrap();
-    let origin_hash = hasher.hash_image(&img);
+#[derive(clap::Args)]
+struct RebuildArgs {
+    /// Output directory to hash. Can be given multiple times together with
+    /// --hashes-file-path to rebuild one store shared by several output directories.
+    #[clap(short, long, default_value = "./output")]
+    output_dir: Vec<PathBuf>,
+
+    /// Hash store to rebuild. Defaults to hashes, or hashes.db with --store sqlite, inside
+    /// the output directory.
+    #[clap(long, visible_alias = "hash-file")]
+    hashes_file_path: Option<PathBuf>,
+
+    /// Format of the converted images to hash.
+    #[clap(long, value_enum, default_value = "avif")]
+    output_format: OutputFormat,
+
+    /// Move outputs within --distance-threshold of an already kept image into a
+    /// duplicates/ subfolder instead of recording them.
+    #[clap(long)]
+    dedup: bool,
+
+    /// Worker threads. 0 uses every logical core.
+    #[clap(short, long, default_value = "0")]
+    threads: usize,
+
+    #[clap(flatten)]
+    hash: HashArgs,
+}
+
+#[derive(clap::Args)]
+struct StatsArgs {
+    #[clap(short, long, default_value = "./output")]
+    output_dir: PathBuf,
+
+    /// Hash store to report on. Defaults to ./hashes, or ./hashes.db with --store sqlite.
+    #[clap(long)]
+    hashes_file_path: Option<PathBuf>,
+
+    #[clap(long, value_enum, default_value = "flat")]
+    store: StoreKind,
+}
+
+#[derive(clap::Args)]
+struct CompactArgs {
+    /// Hash store to compact. Defaults to ./hashes, or ./hashes.db with --store sqlite.
+    #[clap(long)]
+    hashes_file_path: Option<PathBuf>,
+
+    #[clap(flatten)]
+    hash: HashArgs,
+}
+
+#[derive(clap::Args)]
+struct HashImageArgs {
+    /// Images to hash.
+    #[clap(required = true)]
+    paths: Vec<PathBuf>,
+
+    /// Perceptual hash algorithm, as for convert.
+    #[clap(long, value_enum, default_value = "doublegradient")]
+    hash_alg: HashAlgArg,
+
+    /// Hash size as WxH or a single square dimension, as for convert.
+    #[clap(long, default_value = "64x64")]
+    hash_size: HashSize,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum HashAlgArg {
+    Mean,
+    Gradient,
+    #[value(name = "doublegradient")]
+    DoubleGradient,
+    Blockhash,
+    #[value(name = "vertgradient")]
+    VertGradient,
+}
 
-    // 从文件读取哈希值
-    let hashes = HASHES.get().unwrap().read().unwrap();
+impl From<HashAlgArg> for HashAlg {
+    fn from(alg: HashAlgArg) -> Self {
+        match alg {
+            HashAlgArg::Mean => HashAlg::Mean,
+            HashAlgArg::Gradient => HashAlg::Gradient,
+            HashAlgArg::DoubleGradient => HashAlg::DoubleGradient,
+            HashAlgArg::Blockhash => HashAlg::Blockhash,
+            HashAlgArg::VertGradient => HashAlg::VertGradient,
+        }
+    }
+}
 
-    for hash in hashes.iter() {
-        if ((4096 - hash.dist(&origin_hash)) as f32 / 4096.0) > 0.9 {
-            return Ok(None);
+fn main() {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    match cli
+        .command
+        .unwrap_or(Command::Convert(Box::new(cli.convert)))
+    {
+        Command::Convert(mut args) => {
+            // 省略子命令时convert的参数在顶层
+            args.apply_config(matches.subcommand_matches("convert").unwrap_or(&matches));
+            init_threads(args.threads, args.quiet)
+                .unwrap_or_else(|e| exit_fatal(format!("Failed to create thread pool: {}", e)))
+                .install(|| convert(*args))
         }
+        Command::Rebuild(args) => rebuild(args),
+        Command::Stats(args) => stats(args),
+        Command::Compact(args) => compact(args),
+        Command::Hash(args) => hash(args),
+    }
+}
+
+// 哈希存储的路径被目录等非普通文件占用时报错退出，否则读取时的错误让人摸不着头脑
+fn check_store_path(path: &Path) {
+    if path.exists() && !path.is_file() {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!(
+                    "Hash store {} exists but is not a regular file, move or rename it and run again",
+                    path.display()
+                ),
+            )
+            .exit();
     }
+}
+
+// 按参数创建线程池，在它的install中运行的并行迭代都使用这个线程池
+fn init_threads(
+    threads: usize,
+    quiet: bool,
+) -> Result<rayon::ThreadPool, rayon::ThreadPoolBuildError> {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let threads = if threads == 0 { cores } else { threads };
+    if threads > cores * 4 {
+        eprintln!(
+            "--threads {} is far above the {} available cores and will mostly add overhead",
+            threads, cores
+        );
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+    if !quiet {
+        eprintln!("Using {} threads", threads);
+    }
+    Ok(pool)
+}
 
-    Ok(Some(origin_hash))
+fn rebuild(args: RebuildArgs) {
+    let distance_threshold = args.hash.distance_threshold();
+    let pool = init_threads(args.threads, false)
+        .unwrap_or_else(|e| exit_fatal(format!("Failed to create thread pool: {}", e)));
+    let context = args.hash.context();
+    let hashes_file_path = match (args.hashes_file_path, args.output_dir.as_slice()) {
+        (Some(path), _) => path,
+        (None, [output_dir]) => output_dir.join(args.hash.store.file_name()),
+        (None, _) => Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--hashes-file-path is required when rebuilding several output directories",
+            )
+            .exit(),
+    };
+    check_store_path(&hashes_file_path);
+    pool.install(|| {
+        rebuild_hashes(
+            &context,
+            &args.output_dir,
+            &hashes_file_path,
+            args.output_format,
+            args.dedup.then_some(distance_threshold),
+            args.hash.force,
+            args.hash.store,
+        )
+        .unwrap_or_else(|e| exit_fatal(e))
+    });
 }
 
-// 初始化HASHES
-fn init_hashes(hashes_file_path: &str) -> RwLock<HashSet<ImageHash>> {
-    let hashes = RwLock::new(HashSet::new());
-    let hash_file_path = Path::new(hashes_file_path);
-    if hash_file_path.exists() {
-        let file = std::fs::read_to_string(hash_file_path).unwrap();
-        for line in file.lines().filter(|l| !l.is_empty()) {
-            if let Ok(hash) = ImageHash::from_base64(line) {
-                hashes.write().unwrap().insert(hash);
+// 逐个打印图片的哈希，正好两张时再打印它们的距离
+fn hash(args: HashImageArgs) {
+    let context = Context::new(args.hash_alg.into(), args.hash_size);
+    let mut out = std::io::stdout().lock();
+    let mut hashes = Vec::new();
+    let mut failed = false;
+    for path in &args.paths {
+        match context.hash_image(path) {
+            Ok(hash) => {
+                check_output(writeln!(out, "{}  {}", hash.to_base64(), path.display()));
+                hashes.push(hash);
+            }
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                failed = true;
             }
         }
     }
-    hashes
+    if let [a, b] = hashes.as_slice()
+        && args.paths.len() == 2
+    {
+        check_output(writeln!(
+            out,
+            "Distance: {} of {} bits",
+            a.dist(b),
+            args.hash_size.bits()
+        ));
+    }
+    if failed {
+        std::process::exit(EXIT_ERRORS);
+    }
 }
 
-fn init_pb(len: usize) -> ProgressBar {
-    let pb = ProgressBar::new(len as u64);
-    pb.set_style(
-        ProgressStyle::with_template(
-            "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} {msg}",
-        )
-        .unwrap()
-        .with_key("eta", |state: &ProgressState, w: &mut dyn fmt::Write| {
-            write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
-        })
-        .progress_chars("#>-"),
+fn compact(args: CompactArgs) {
+    let distance_threshold = args.hash.distance_threshold();
+    let path = args
+        .hashes_file_path
+        .unwrap_or_else(|| PathBuf::from(args.hash.store.file_name()));
+    if !path.exists() {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!("Hash store {} does not exist", path.display()),
+            )
+            .exit();
+    }
+    check_store_path(&path);
+    let mut store = open_store(
+        args.hash.store,
+        &path,
+        args.hash.hash_alg.into(),
+        args.hash.hash_size,
+        args.hash.force,
+    )
+    .unwrap_or_else(|e| exit_fatal(e));
+    let collapsed = compact_hashes(store.as_mut(), distance_threshold)
+        .unwrap_or_else(|e| exit_fatal(format!("Failed to compact {}: {}", path.display(), e)));
+    println!(
+        "Collapsed {} hashes within distance {} in {}",
+        collapsed,
+        distance_threshold,
+        path.display()
     );
-    pb.enable_steady_tick(Duration::from_millis(100));
-    pb
 }
 
-fn rebuild_hashes(output_dir: &str) {
-    let hash_file_path = Path::new(output_dir).join("hashes");
-    let files = read_dir(output_dir).expect("Failed to read directory");
+fn convert(args: ConvertArgs) {
+    let timings = Timings::new(args.timings);
+    let hash_alg = HashAlg::from(args.hash.hash_alg);
+    let hash_size = args.hash.hash_size;
+    let distance_threshold = args.hash.distance_threshold();
 
-    let hashes = Mutex::new(Vec::new());
+    if args.lossless && args.output_format == OutputFormat::Avif {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--lossless is not supported for AVIF output, the encoder has no lossless mode. \
+                 Use --output-format webp or png",
+            )
+            .exit();
+    }
+    if args.lossless && args.quality_given {
+        eprintln!("Warning: --quality has no effect with --lossless, output is encoded losslessly");
+    }
+    if args.target_size.is_some() && args.output_format != OutputFormat::Avif {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--target-size only applies to AVIF output, WebP and PNG are encoded losslessly",
+            )
+            .exit();
+    }
 
-    let file_vec: Vec<_> = files.flatten().collect();
+    if let Some(template) = &args.progress_template
+        && let Err(e) = ProgressStyle::with_template(template)
+    {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!("Invalid --progress-template: {}", e),
+            )
+            .exit();
+    }
+    let progress = Progress {
+        template: args.progress_template.as_deref(),
+        plain: args.no_progress || !std::io::stderr().is_terminal(),
+    };
 
-    let pb = ProgressBar::new(file_vec.len() as u64);
-    pb.set_style(
-        ProgressStyle::with_template(
-            "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} {msg}",
-        )
-        .unwrap()
-        .with_key("eta", |state: &ProgressState, w: &mut dyn fmt::Write| {
-            write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap()
-        })
-        .progress_chars("#>-"),
-    );
-    pb.enable_steady_tick(Duration::from_millis(100));
+    let threads = rayon::current_num_threads();
+    let mut context = args.hash.context();
+    context.io_retries = args.io_retries;
 
-    file_vec.par_iter().for_each(|file| {
-        let path = file.path();
-        if path.is_file() && path.extension().is_some_and(|e| e == "avif") {
-            let img = image::ImageReader::open(path)
-                .unwrap()
-                .with_guessed_format()
-                .unwrap()
-                .decode()
-                .unwrap();
-            let hasher = HASHER.get().unwrap();
-            let hash = hasher.hash_image(&img);
-            hashes.lock().unwrap().push(hash.clone());
-            pb.inc(1);
+    let options = ConvertOptions {
+        speed: args.speed,
+        quality: args.quality,
+        distance_threshold,
+        output_format: args.output_format,
+        max_width: args.max_width,
+        max_height: args.max_height,
+        keep_metadata: args.keep_metadata && !args.strip_metadata,
+        thumbnail: args.thumbnail,
+        target_size: args.target_size,
+    };
+
+    if args.benchmark {
+        // 和其他输出一样，管道被提前关闭不算错误
+        if let Err(e) = benchmark(&context, &options)
+            && !matches!(&e, ImageError::IoError(e) if e.kind() == std::io::ErrorKind::BrokenPipe)
+        {
+            exit_fatal(format!("Benchmark failed: {}", e));
+        }
+        return;
+    }
+
+    if args.source_dir.is_empty()
+        && args.from_file.is_none()
+        && !args.stdin
+        && args.url_list.is_none()
+    {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "Please provide at least one --source-dir, or --from-file, --stdin or --url-list",
+            )
+            .exit();
+    }
+    // 单个文件直接加入图片列表，路径写错时直接报错，而不是当作没有图片
+    let (source_dirs, source_files): (Vec<&Path>, Vec<&Path>) = args
+        .source_dir
+        .iter()
+        .map(PathBuf::as_path)
+        .partition(|path| path.is_dir());
+    for source in &source_files {
+        if !source.exists() {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("Source {} does not exist", source.display()),
+                )
+                .exit();
+        }
+    }
+    let filter = PathFilter::new(&args.include, &args.exclude).unwrap_or_else(|e| {
+        Cli::command()
+            .error(clap::error::ErrorKind::ValueValidation, e)
+            .exit()
+    });
+    let mut find_options = FindOptions {
+        formats: args
+            .formats
+            .iter()
+            .map(|f| f.trim().trim_start_matches('.').to_ascii_lowercase())
+            .collect(),
+        skip_dirs: Vec::new(),
+        follow_symlinks: args.follow_symlinks,
+        ignore_files: !args.no_ignore_files,
+    };
+    if args.reencode {
+        find_options
+            .formats
+            .extend(REENCODE_FORMATS.map(String::from));
+    }
+    // 输入包含输出格式时，输出到源目录本身会在下次运行时把输出当作新图片再转换一遍
+    if find_options
+        .formats
+        .iter()
+        .any(|f| f == args.output_format.extension())
+        && let Ok(output) = args.output_dir.canonicalize()
+        && source_dirs
+            .iter()
+            .any(|dir| dir.canonicalize().is_ok_and(|dir| dir == output))
+    {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!(
+                    "Output directory {} is also a source directory, converting {} images into \
+                     it would pick up its own outputs. Use a different --output-dir",
+                    args.output_dir.display(),
+                    args.output_format.extension()
+                ),
+            )
+            .exit();
+    }
+    // 输出目录或移动源文件的目录在源目录内时跳过它，避免把之前的输出当作新图片处理
+    for (name, dir) in [
+        ("Output", Some(&args.output_dir)),
+        ("Move", args.move_source.as_ref()),
+    ] {
+        let Some(Ok(dir_path)) = dir.map(|d| d.canonicalize()) else {
+            continue;
+        };
+        for source_dir in &source_dirs {
+            if source_dir
+                .canonicalize()
+                .is_ok_and(|source| dir_path.starts_with(source))
+            {
+                eprintln!(
+                    "{} directory {} is inside source directory {}, skipping it during discovery",
+                    name,
+                    dir.unwrap().display(),
+                    source_dir.display()
+                );
+                find_options.skip_dirs.push(dir_path);
+                break;
+            }
         }
+    }
+    let discovery = timings.start(Stage::Discovery);
+    let spinner = init_spinner();
+    if progress.plain {
+        spinner.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    let mut images: Vec<PathBuf> = Vec::new();
+    for source_dir in &source_dirs {
+        let found = find_images_with(source_dir, &find_options, &spinner);
+        if found.is_empty() {
+            spinner.suspend(|| {
+                eprintln!(
+                    "Warning: no {} images found in {}",
+                    find_options.formats.join("/"),
+                    source_dir.display()
+                )
+            });
+        }
+        images.extend(found);
+    }
+    spinner.finish();
+    drop(discovery);
+    for source in source_files {
+        if !find_options.matches_format(source) {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!(
+                        "Source {} is not a supported image format",
+                        source.display()
+                    ),
+                )
+                .exit();
+        }
+        images.push(source.to_path_buf());
+    }
+    let list =
+        if let Some(list) = &args.from_file {
+            Some(std::fs::read(list).unwrap_or_else(|e| {
+                exit_fatal(format!("Failed to read {}: {}", list.display(), e))
+            }))
+        } else if args.stdin {
+            let mut content = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut content)
+                .unwrap_or_else(|e| exit_fatal(format!("Failed to read standard input: {}", e)));
+            Some(content)
+        } else {
+            None
+        };
+    for path in list.iter().flat_map(|l| list_paths(l)) {
+        if !path.is_file() {
+            eprintln!("Skipping {}: no such file", path.display());
+        } else if !find_options.matches_format(&path) {
+            eprintln!("Skipping {}: not a supported image format", path.display());
+        } else {
+            images.push(path);
+        }
+    }
+    // URL在处理时才下载，先只检查能否从中得到安全的文件名和支持的格式
+    let mut urls = Vec::new();
+    let fetcher = args.url_list.as_ref().map(|list| {
+        let content = std::fs::read_to_string(list)
+            .unwrap_or_else(|e| exit_fatal(format!("Failed to read {}: {}", list.display(), e)));
+        for url in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            match url_file_name(url) {
+                Some(name) if find_options.matches_format(Path::new(name)) => {
+                    urls.push(url.to_string())
+                }
+                Some(_) => eprintln!("Skipping {}: not a supported image format", url),
+                None => eprintln!("Skipping {}: the URL has no usable file name", url),
+            }
+        }
+        Fetcher::new(args.max_fetches as usize)
+            .unwrap_or_else(|e| exit_fatal(format!("Can't fetch --url-list: {}", e)))
     });
+    // 多个源目录可能重叠，同一个文件只处理一次
+    images.sort();
+    images.dedup();
+    images.retain(|path| filter.matches(path));
+    if let Some(since) = args.since {
+        let found = images.len();
+        images.retain(
+            |path| match std::fs::metadata(path).and_then(|m| m.modified()) {
+                Ok(modified) => modified >= since,
+                Err(e) => {
+                    eprintln!(
+                        "Warning: can't read the modification time of {}, processing it anyway: {}",
+                        path.display(),
+                        e
+                    );
+                    true
+                }
+            },
+        );
+        if !args.quiet {
+            eprintln!(
+                "{} of {} images were modified after the --since time",
+                images.len(),
+                found
+            );
+        }
+    }
+    if args.watch && source_dirs.is_empty() {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--watch needs at least one source directory",
+            )
+            .exit();
+    }
+    // 本地图片在前，URL在后，之后的抽样和数量限制对两者一起生效
+    urls.sort();
+    urls.dedup();
+    let mut images: Vec<Source> = images
+        .into_iter()
+        .map(Source::File)
+        .chain(urls.into_iter().map(Source::Url))
+        .collect();
+    if images.is_empty() && !args.watch {
+        eprintln!("Error: no images to process");
+        std::process::exit(EXIT_FATAL);
+    }
+    if let Some(n) = args.sample {
+        let seed = args.seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64)
+        });
+        let found = images.len();
+        sample(&mut images, n as usize, seed);
+        if !args.quiet {
+            eprintln!(
+                "Sampled {} of {} images with --seed {}",
+                images.len(),
+                found,
+                seed
+            );
+        }
+    }
+    if let Some(n) = args.max_images
+        && images.len() as u64 > n
+    {
+        let found = images.len();
+        images.truncate(n as usize);
+        if !args.quiet {
+            eprintln!("Limited to the first {} of {} images", n, found);
+        }
+    }
 
-    let mut file = std::fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&hash_file_path)
-        .unwrap();
-    for hash in hashes.lock().unwrap().iter() {
-        writeln!(file, "{}", hash.to_base64()).unwrap();
+    let verbosity = if args.quiet {
+        Verbosity::Quiet
+    } else if args.verbose {
+        Verbosity::Verbose
+    } else {
+        Verbosity::Normal
+    };
+    // --url-list与查找重复和试运行冲突，这两种模式下只有本地文件
+    let files: Vec<PathBuf> = images
+        .iter()
+        .filter_map(|source| match source {
+            Source::File(path) => Some(path.clone()),
+            Source::Url(_) => None,
+        })
+        .collect();
+
+    if let Some(report) = &args.find_duplicates {
+        let errors = find_duplicates(
+            &context,
+            &files,
+            distance_threshold,
+            report,
+            args.contact_sheet.as_deref(),
+            verbosity,
+            &progress,
+        );
+        if errors > 0 {
+            std::process::exit(EXIT_ERRORS);
+        }
+        return;
     }
 
-    println!(
-        "Hashes have been rebuilt and saved to {}",
-        hash_file_path.display()
-    );
+    let hashes_file_path = args
+        .hashes_file_path
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(args.hash.store.file_name()));
+    check_store_path(&hashes_file_path);
+    let store = open_store(
+        args.hash.store,
+        &hashes_file_path,
+        hash_alg,
+        hash_size,
+        args.hash.force,
+    )
+    .unwrap_or_else(|e| exit_fatal(e));
+    let store: Box<dyn HashStore> = if args.reference_hashes.is_empty() {
+        store
+    } else {
+        if let Some(missing) = args.reference_hashes.iter().find(|p| !p.is_file()) {
+            Cli::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!("Reference hashes file {} does not exist", missing.display()),
+                )
+                .exit();
+        }
+        let reference =
+            load_reference_hashes(&args.reference_hashes, hash_alg, hash_size, args.hash.force)
+                .unwrap_or_else(|e| exit_fatal(e));
+        if !args.quiet {
+            eprintln!("Loaded {} reference hashes", reference.len());
+        }
+        Box::new(ReferenceStore::new(store, reference))
+    };
+    // 试运行不应写入存储，只在内存中继续查重
+    if args.dry_run {
+        context.set_store(Box::new(store.into_memory()));
+    } else {
+        context.set_store(store);
+    }
+    // 超时处理需要在单独的线程上使用
+    let context = Arc::new(context);
+
+    if args.dry_run {
+        let histogram = args.histogram.then(|| Histogram::new(hash_size.bits()));
+        let min_size = MinSize {
+            width: args.min_width,
+            height: args.min_height,
+        };
+        let errors = dry_run(
+            &context,
+            &files,
+            &options,
+            verbosity,
+            &progress,
+            &min_size,
+            args.animated,
+            histogram.as_ref(),
+        );
+        if let Some(histogram) = histogram {
+            check_output(histogram.print(&mut std::io::stdout()));
+        }
+        if errors > 0 {
+            std::process::exit(EXIT_ERRORS);
+        }
+        return;
+    }
+
+    if args.remove_source && !args.yes && !images.is_empty() {
+        eprint!(
+            "Source images will be deleted after conversion ({} found). Continue? [y/N] ",
+            images.len()
+        );
+        let mut answer = String::new();
+        // 读不到回答时按拒绝处理，不能在没有确认的情况下删除源文件
+        if let Err(e) = std::io::stdin().read_line(&mut answer) {
+            exit_fatal(format!("Failed to read the answer, aborting: {}", e));
+        }
+        if !answer.trim().eq_ignore_ascii_case("y") {
+            eprintln!("Aborted");
+            return;
+        }
+    }
+
+    let output_dir = args.output_dir.as_path();
+    if !output_dir.exists() {
+        std::fs::create_dir_all(output_dir).unwrap_or_else(|e| {
+            exit_fatal(format!("Failed to create {}: {}", output_dir.display(), e))
+        });
+    }
+    let removed = remove_partial_outputs(output_dir);
+    if removed > 0 {
+        eprintln!(
+            "Removed {} partially written outputs left by an interrupted run",
+            removed
+        );
+    }
+
+    if let Some(archive) = &args.archive
+        && archive.extension().is_none_or(|ext| ext != "tar")
+    {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!(
+                    "--archive {} must be a .tar file, other archive formats are not supported",
+                    archive.display()
+                ),
+            )
+            .exit();
+    }
+    let archive = args.archive.as_ref().map(|path| {
+        TarArchive::create(path)
+            .unwrap_or_else(|e| exit_fatal(format!("Failed to create {}: {}", path.display(), e)))
+    });
+    // 归档中的条目名，与输出目录中的相对路径一致，统一使用/分隔
+    let entry_name = |path: &Path| {
+        path.strip_prefix(output_dir)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/")
+    };
+    // --shard时把输出移到按文件名分出的子目录中，目录在写入前创建
+    let place = |path: PathBuf| match args.shard {
+        Some(levels) => sharded(&path, levels),
+        None => path,
+    };
+
+    // 记录源文件与输出文件对应关系的清单
+    let manifest_file = Mutex::new(
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(output_dir.join(MANIFEST_FILE_NAME))
+            .unwrap_or_else(|e| {
+                exit_fatal(format!("Failed to open {}: {}", MANIFEST_FILE_NAME, e))
+            }),
+    );
+
+    let extension = options.output_format.extension();
+    let thumbnails_dir = output_dir.join(THUMBNAILS_DIR_NAME);
+
+    // 清单里的路径按显示形式保存，非UTF-8路径同样按显示形式查找
+    let converted: HashMap<String, ManifestEntry> = if args.resume {
+        read_manifest(output_dir)
+            .into_iter()
+            .map(|entry| (entry.source.clone(), entry))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    // 清单中记录的源文件内容哈希，对应保留图片在存储中的记录编号
+    let exact: Mutex<HashMap<String, usize>> = Mutex::new(if args.exact_dedup {
+        read_manifest(output_dir)
+            .into_iter()
+            .filter_map(|entry| {
+                let hash = ImageHash::from_base64(&entry.hash).ok()?;
+                Some((entry.content_hash?, context.find_hash(&hash)?))
+            })
+            .collect()
+    } else {
+        HashMap::new()
+    });
+
+    // 已保留的图片，按存储中的记录编号索引，--keep-best用来取代它们，--report用来找到重复图片对应的文件
+    let track_kept = args.keep_best || args.report.is_some();
+    let kept: Mutex<HashMap<usize, Kept>> = Mutex::new(if track_kept {
+        let mut kept = HashMap::new();
+        for entry in read_manifest(output_dir) {
+            if let Ok(hash) = ImageHash::from_base64(&entry.hash)
+                && let Some(id) = context.find_hash(&hash)
+                && output_dir.join(&entry.output).exists()
+            {
+                kept.entry(id)
+                    .or_insert_with(|| Kept::from_entry(output_dir, &entry));
+            }
+        }
+        kept
+    } else {
+        HashMap::new()
+    });
+
+    let summary = Summary::default();
+    let min_size = MinSize {
+        width: args.min_width,
+        height: args.min_height,
+    };
+    let histogram = Histogram::new(hash_size.bits());
+    let json_stdout = args.json.as_deref() == Some(Path::new("-"));
+    let json = JsonLines::new(args.json.as_ref().map(|path| {
+        let writer: Box<dyn Write + Send> = if json_stdout {
+            Box::new(std::io::stdout())
+        } else {
+            Box::new(std::fs::File::create(path).unwrap_or_else(|e| {
+                exit_fatal(format!("Failed to create {}: {}", path.display(), e))
+            }))
+        };
+        writer
+    }));
+    // 结果输出到stdout时，其他信息改为输出到stderr
+    let mut out: Box<dyn Write> = if json_stdout {
+        Box::new(std::io::stderr())
+    } else {
+        Box::new(std::io::stdout())
+    };
+    let colors = if json_stdout {
+        console::colors_enabled_stderr()
+    } else {
+        console::colors_enabled()
+    };
+    let timeout = args.timeout_secs.map(Duration::from_secs);
+    // 第一个导致停止的写入错误
+    let write_failed = Mutex::new(None);
+    // 跳过的重复图片及其匹配到的哈希，结束后写入报告
+    let duplicates = Mutex::new(Vec::new());
+    // --name-template中{index}的计数
+    let named = AtomicU64::new(0);
+    // 本次运行写入的输出，--verify-dedup时结束后检查
+    let written = Mutex::new(Vec::new());
+    // 本次运行写入的输出和缩略图的总字节数，达到--max-output-bytes时停止
+    let output_total = AtomicU64::new(0);
+    let budget_reached = AtomicBool::new(false);
+    // 许可可能随超时的编码移到后台线程，用Arc共享
+    let encodes = Arc::new(Semaphore::new(
+        args.max_concurrent_encodes.map_or(threads, |n| n as usize),
+    ));
+
+    // 第一次Ctrl-C只停止领取新图片，等正在转换的图片写完；第二次保存已记录的哈希后直接退出
+    static STOP: AtomicBool = AtomicBool::new(false);
+    let interrupted_context = Arc::downgrade(&context);
+    ctrlc::set_handler(move || {
+        if STOP.swap(true, Ordering::SeqCst) {
+            if let Some(context) = interrupted_context.upgrade() {
+                let _ = context.sync_hashes();
+            }
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+        eprintln!("Interrupted, finishing in-flight images. Press Ctrl-C again to abort");
+    })
+    .unwrap_or_else(|e| exit_fatal(format!("Failed to set Ctrl-C handler: {}", e)));
+
+    // 定期写入存储缓冲的哈希，进程被强制结束时已写入的输出大多也有哈希记录，转换结束后线程随之退出
+    let flushed_context = Arc::downgrade(&context);
+    std::thread::spawn(move || {
+        loop {
+            std::thread::sleep(HASH_FLUSH_INTERVAL);
+            let Some(context) = flushed_context.upgrade() else {
+                break;
+            };
+            // 写入失败时缓冲保留，结束时的sync会报告错误
+            let _ = context.flush_hashes();
+        }
+    });
+
+    // 进度条，剩余时间按字节数估算
+    // 下载前不知道URL的大小，只按本地文件估算
+    let total_bytes = images
+        .par_iter()
+        .map(|source| match source {
+            Source::File(path) => std::fs::metadata(path).map_or(0, |m| m.len()),
+            Source::Url(_) => 0,
+        })
+        .sum();
+    let done_bytes = Arc::new(AtomicU64::new(0));
+    let pb = init_pb_weighted(
+        images.len(),
+        total_bytes,
+        done_bytes.clone(),
+        progress.template,
+    );
+    progress.start(&pb, verbosity);
+    let log = Log { pb: &pb, verbosity };
+    // --json写入失败时和磁盘写满一样停止，不再提交新的图片
+    let json_failed = || {
+        if let Some(e) = json.take_error() {
+            write_failed
+                .lock()
+                .unwrap()
+                .get_or_insert(format!("Failed to write --json output: {}", e));
+            STOP.store(true, Ordering::SeqCst);
+        }
+    };
+    let process = |source: &Source| {
+        json_failed();
+        if STOP.load(Ordering::SeqCst) {
+            return;
+        }
+        let img_path = source.path();
+        let started = Instant::now();
+        // 清单里记录过且输出仍存在，说明上次运行已经转换过，URL也不必再下载
+        let existing = converted
+            .get(&img_path.display().to_string())
+            .filter(|entry| output_dir.join(&entry.output).exists());
+        let fetched = match source {
+            Source::Url(url) if existing.is_none() => Some(fetcher.as_ref().unwrap().fetch(url)),
+            _ => None,
+        };
+        let bytes_in = match (source, &fetched) {
+            (Source::File(path), _) => std::fs::metadata(path).map_or(0, |m| m.len()),
+            (Source::Url(_), Some(Ok(data))) => data.len() as u64,
+            (Source::Url(_), _) => 0,
+        };
+        let _tick = Tick {
+            pb: &pb,
+            done_bytes: &done_bytes,
+            bytes: if fetched.is_some() { 0 } else { bytes_in },
+        };
+        let record = |status| JsonRecord {
+            source: img_path.display().to_string(),
+            status,
+            bytes_in,
+            ..JsonRecord::default()
+        };
+        let fail = |failure: Failure, message: String| {
+            json.emit(&JsonRecord {
+                error: Some(message.clone()),
+                ..record("error")
+            });
+            log.error(message);
+            summary.errors.fetch_add(1, Ordering::Relaxed);
+            summary
+                .failures
+                .lock()
+                .unwrap()
+                .push((failure, img_path.to_path_buf()));
+        };
+        // 磁盘写满或记录写入失败时，继续处理只会失败或让记录不一致，停止提交新的图片
+        let abort = |message: String| {
+            write_failed.lock().unwrap().get_or_insert(message.clone());
+            STOP.store(true, Ordering::SeqCst);
+            fail(Failure::Write, message);
+        };
+        let duplicate = |of: usize, distance: u32| {
+            json.emit(&JsonRecord {
+                matched: context.stored_hash(of).map(|hash| hash.to_base64()),
+                distance: Some(distance),
+                ..record("duplicate")
+            });
+            duplicates
+                .lock()
+                .unwrap()
+                .push((img_path.to_path_buf(), of, distance));
+            summary.duplicates.fetch_add(1, Ordering::Relaxed);
+        };
+
+        if let Some(entry) = existing {
+            if let Ok(hash) = ImageHash::from_base64(&entry.hash) {
+                // 哈希已在存储中时不会写入，只是让本次运行的判重能看到它
+                match context.try_insert_hash(&hash.into(), 0, &HashMeta::default()) {
+                    Ok(Inserted::Stored(id)) if track_kept => {
+                        kept.lock()
+                            .unwrap()
+                            .entry(id)
+                            .or_insert_with(|| Kept::from_entry(output_dir, entry));
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        abort(format!(
+                            "Failed to save hash to {}: {}",
+                            hashes_file_path.display(),
+                            e
+                        ));
+                        return;
+                    }
+                }
+            }
+            log.info(format!(
+                "Image {} already converted to {}",
+                img_path.display(),
+                entry.output
+            ));
+            json.emit(&JsonRecord {
+                output: Some(output_dir.join(&entry.output).display().to_string()),
+                hash: Some(entry.hash.clone()),
+                ..record("existing")
+            });
+            summary.existing.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        // 扫描后被删除或为空的文件直接报错，不必尝试解码
+        let (data, source_bytes, source_mtime) = match fetched {
+            None => match retry_io(args.io_retries, || std::fs::metadata(img_path)) {
+                Ok(metadata) => (
+                    SourceData::File(img_path.to_path_buf()),
+                    metadata.len(),
+                    metadata.modified().ok(),
+                ),
+                Err(e) => {
+                    fail(
+                        Failure::Read,
+                        format!("Image {} error: {}", img_path.display(), e),
+                    );
+                    return;
+                }
+            },
+            Some(Ok(data)) => (SourceData::Memory(data.into()), bytes_in, None),
+            Some(Err(e)) => {
+                fail(
+                    Failure::Fetch,
+                    format!("Image {} error: {}", img_path.display(), e),
+                );
+                return;
+            }
+        };
+        if source_bytes == 0 {
+            fail(
+                Failure::Read,
+                format!("Image {} is empty", img_path.display()),
+            );
+            return;
+        }
+
+        // 只读取文件头的尺寸，太小的图片不参与判重和转换
+        if let Some((width, height)) = min_size.rejects(&data) {
+            log.info(format!(
+                "Image {} is too small ({}x{}), skipping",
+                img_path.display(),
+                width,
+                height
+            ));
+            json.emit(&record("too_small"));
+            summary.too_small.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        if args.animated != AnimatedPolicy::FirstFrame && data.is_animated().unwrap_or(false) {
+            if args.animated == AnimatedPolicy::Error {
+                fail(
+                    Failure::Animated,
+                    format!("Image {} is animated", img_path.display()),
+                );
+            } else {
+                log.info(format!(
+                    "Image {} is animated, skipping",
+                    img_path.display()
+                ));
+                json.emit(&record("animated"));
+                summary.animated.fetch_add(1, Ordering::Relaxed);
+            }
+            return;
+        }
+
+        let content = if args.name_by_hash || args.exact_dedup {
+            match data.content_hash(args.io_retries) {
+                Ok(content) => Some(content),
+                Err(e) => {
+                    fail(
+                        Failure::Read,
+                        format!("Image {} error: {}", img_path.display(), e),
+                    );
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+
+        // 内容完全相同的文件不必解码和计算感知哈希
+        if args.exact_dedup
+            && let Some(&of) = exact.lock().unwrap().get(content.as_ref().unwrap())
+        {
+            log.info(format!(
+                "Image {} is an exact copy of an existing image",
+                img_path.display()
+            ));
+            duplicate(of, 0);
+            return;
+        }
+
+        // 按内容命名时输出已存在说明之前转换过，除非要求覆盖，直接跳过
+        let hashed_path = if args.name_by_hash {
+            let path =
+                place(output_dir.join(format!("{}.{}", content.as_ref().unwrap(), extension)));
+            let exists = match &archive {
+                Some(archive) => archive.contains(&entry_name(&path)),
+                None => path.exists(),
+            };
+            if exists && args.on_collision != CollisionPolicy::Overwrite {
+                log.info(format!(
+                    "Image {} already converted to {}",
+                    img_path.display(),
+                    path.display()
+                ));
+                json.emit(&JsonRecord {
+                    output: Some(path.display().to_string()),
+                    ..record("existing")
+                });
+                summary.existing.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Some(path)
+        } else {
+            None
+        };
+
+        // 找到相同的图片
+        let verbose = log.verbosity == Verbosity::Verbose;
+        let looked_up = {
+            let _hashing = timings.start(Stage::Hashing);
+            let data = data.clone();
+            let context = context.clone();
+            with_timeout(timeout, move || {
+                lookup(
+                    &context,
+                    &data,
+                    options.distance_threshold,
+                    verbose || args.histogram,
+                )
+            })
+        };
+        let looked_up = match looked_up {
+            Ok(looked_up) => looked_up,
+            Err(Unfinished::TimedOut) => {
+                fail(
+                    Failure::Timeout,
+                    format!(
+                        "Image {} timed out after {}s while hashing",
+                        img_path.display(),
+                        timeout.unwrap().as_secs()
+                    ),
+                );
+                return;
+            }
+            Err(Unfinished::Panicked) => {
+                fail(
+                    Failure::Decode,
+                    format!("Image {} panicked while hashing", img_path.display()),
+                );
+                return;
+            }
+        };
+        if args.histogram
+            && let Ok(looked_up) = &looked_up
+        {
+            histogram.record(looked_up.nearest);
+        }
+        let looked_up = match looked_up {
+            Ok(looked_up) => looked_up,
+            Err(e) => {
+                fail(
+                    Failure::Decode,
+                    format!("Image {} error: {:?}", img_path.display(), e),
+                );
+                return;
+            }
+        };
+        let nearest = looked_up.nearest;
+        let mut replacing = match looked_up.decision {
+            HashDecision::Novel(_) => None,
+            HashDecision::Duplicate { of, distance } => {
+                let claim = if args.keep_best {
+                    Claim::if_better(&kept, of, data.pixels(), source_bytes)
+                } else {
+                    None
+                };
+                // 重复图片质量更高时转换它，取代之前保留的图片
+                if claim.is_none() {
+                    log.info(format!("Image {} already exists", img_path.display()));
+                    log.debug(format!(
+                        "Image {} nearest distance {}, checked in {:.2?}",
+                        img_path.display(),
+                        format_distance(nearest),
+                        started.elapsed()
+                    ));
+                    duplicate(of, distance);
+                    return;
+                }
+                claim
+            }
+        };
+        let LookedUp {
+            decoded, hashes, ..
+        } = looked_up;
+        let hash = hashes.canonical();
+        if replacing.is_none() {
+            log.info(format!("Processing image: {}", img_path.display()));
+        }
+        log.debug(format!(
+            "Image {} hash {} nearest distance {}",
+            img_path.display(),
+            hash.to_base64(),
+            format_distance(nearest)
+        ));
+        // 转换图片格式
+        let mtime = args.preserve_mtime.then_some(source_mtime).flatten();
+        let source_pixels = data.pixels();
+        let converted = {
+            let waiting = timings.start(Stage::EncodeWait);
+            let permit = encodes.acquire();
+            drop(waiting);
+            let _encoding = timings.start(Stage::Encoding);
+            let options = resolve_overrides(&args.overrides, img_path, options);
+            let context = context.clone();
+            // 超时后编码仍在后台进行，编码真正结束时才归还许可，--max-concurrent-encodes始终有效
+            with_timeout(timeout, move || {
+                let _permit = permit;
+                context.convert_decoded(decoded, &options)
+            })
+        };
+        let (img, thumbnail, quality) = match converted {
+            Ok(Ok(Converted {
+                data,
+                thumbnail,
+                quality,
+            })) => (data, thumbnail, quality),
+            Ok(Err(e)) => {
+                let failure = match e {
+                    ImageError::Encoding(_) | ImageError::Unsupported(_) => Failure::Encode,
+                    _ => Failure::Decode,
+                };
+                fail(
+                    failure,
+                    format!("Image {} conversion failed: {}", img_path.display(), e),
+                );
+                return;
+            }
+            Err(Unfinished::TimedOut) => {
+                fail(
+                    Failure::Timeout,
+                    format!(
+                        "Image {} timed out after {}s while converting",
+                        img_path.display(),
+                        timeout.unwrap().as_secs()
+                    ),
+                );
+                return;
+            }
+            Err(Unfinished::Panicked) => {
+                fail(
+                    Failure::Encode,
+                    format!("Image {} panicked while converting", img_path.display()),
+                );
+                return;
+            }
+        };
+
+        // URL按路径的最后一段命名，不在任何源目录下，保持目录结构时直接放在输出目录中
+        let name_path = match source {
+            Source::File(path) => path.as_path(),
+            Source::Url(url) => Path::new(url_file_name(url).unwrap()),
+        };
+        let named_path = if let Some(template) = &args.name_template {
+            let name = template.render(name_path, named.fetch_add(1, Ordering::Relaxed) + 1);
+            let dir = if args.preserve_structure {
+                let relative = relative_to_source(&source_dirs, name_path);
+                output_dir.join(relative.parent().unwrap())
+            } else {
+                output_dir.to_path_buf()
+            };
+            Some(place(dir.join(format!("{}.{}", name, extension))))
+        } else if args.preserve_structure {
+            let relative = relative_to_source(&source_dirs, name_path);
+            Some(output_dir.join(relative).with_extension(extension))
+        } else if args.keep_name {
+            let mut name = name_path.file_stem().unwrap().to_os_string();
+            name.push(".");
+            name.push(extension);
+            Some(place(output_dir.join(name)))
+        } else {
+            None
+        };
+        // 非覆盖策略下claim_output_path会创建空的占位文件，它属于本次调用，放弃写入时要删除
+        let reserved = hashed_path.is_none()
+            && named_path.is_some()
+            && archive.is_none()
+            && args.on_collision != CollisionPolicy::Overwrite;
+        let output_path = if let Some(path) = hashed_path {
+            path
+        } else if let Some(path) = named_path {
+            let claimed = match &archive {
+                Some(archive) => Ok(archive
+                    .claim(&entry_name(&path), args.on_collision)
+                    .map(|name| output_dir.join(name))),
+                None => {
+                    create_parent(&path).and_then(|_| claim_output_path(&path, args.on_collision))
+                }
+            };
+            match claimed {
+                Ok(Some(path)) => path,
+                Err(e) => {
+                    let message = format!("Failed to write {}: {}", path.display(), e);
+                    if is_storage_full(&e) {
+                        abort(message);
+                    } else {
+                        fail(Failure::Write, message);
+                    }
+                    return;
+                }
+                Ok(None) if args.on_collision == CollisionPolicy::Skip => {
+                    log.info(format!(
+                        "Output {} already exists, skipping {}",
+                        path.display(),
+                        img_path.display()
+                    ));
+                    json.emit(&JsonRecord {
+                        output: Some(path.display().to_string()),
+                        ..record("existing")
+                    });
+                    summary.existing.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                Ok(None) => {
+                    fail(
+                        Failure::Exists,
+                        format!("Output {} already exists", path.display()),
+                    );
+                    return;
+                }
+            }
+        } else {
+            place(output_dir.join(format!("{}.{}", uuid::Uuid::now_v7(), extension)))
+        };
+        let _writing = timings.start(Stage::Writing);
+        // 只删除本次写入的临时文件和占位文件，覆盖模式下已有的输出不能因为放弃写入而丢失
+        let discard = |part: Option<&Path>| {
+            if let Some(part) = part {
+                let _ = std::fs::remove_file(part);
+            }
+            if reserved {
+                let _ = std::fs::remove_file(&output_path);
+            }
+        };
+        // 先写入.part临时文件，哈希保存成功后才重命名为输出
+        // 写入归档的条目无法删除，确认不是重复图片后才写入
+        let part_path = if archive.is_none() {
+            let written = create_parent(&output_path)
+                .and_then(|_| write_partial(&output_path, &img, args.io_retries))
+                .and_then(|part| {
+                    // 重命名保留修改时间，先设置到临时文件上
+                    if let Some(mtime) = mtime
+                        && let Err(e) = set_mtime(&part, mtime)
+                    {
+                        let _ = std::fs::remove_file(&part);
+                        return Err(e);
+                    }
+                    Ok(part)
+                });
+            match written {
+                Ok(part) => Some(part),
+                Err(e) => {
+                    discard(None);
+                    let message = format!("Failed to write {}: {}", output_path.display(), e);
+                    if is_storage_full(&e) {
+                        abort(message);
+                    } else {
+                        fail(Failure::Write, message);
+                    }
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+
+        // 保存哈希值，转换期间可能已有相似图片写入
+        let source = img_path.display().to_string();
+        let output = output_path
+            .strip_prefix(output_dir)
+            .unwrap()
+            .display()
+            .to_string();
+        let meta = HashMeta {
+            source: Some(source.clone()),
+            output: Some(output.clone()),
+            distance_threshold: options.distance_threshold,
+        };
+        // 取代失败时恢复旧的哈希
+        let replaced_hash = replacing
+            .as_ref()
+            .and_then(|claim| context.stored_hash(claim.id));
+        let id = if let Some(claim) = &replacing {
+            // 新图片取代之前保留的图片，新的输出写入后才删除旧的输出
+            match context.replace_hash(claim.id, hash.clone(), &meta) {
+                Ok(id) => id,
+                Err(e) => {
+                    discard(part_path.as_deref());
+                    abort(format!(
+                        "Failed to save hash to {}: {}",
+                        hashes_file_path.display(),
+                        e
+                    ));
+                    return;
+                }
+            }
+        } else {
+            match context.try_insert_hash(&hashes, options.distance_threshold, &meta) {
+                Ok(Inserted::Stored(id)) => id,
+                // 没有哈希记录的输出下次运行会被重复转换，不保留它
+                Err(e) => {
+                    discard(part_path.as_deref());
+                    abort(format!(
+                        "Failed to save hash to {}: {}",
+                        hashes_file_path.display(),
+                        e
+                    ));
+                    return;
+                }
+                Ok(Inserted::Duplicate { of, distance }) => {
+                    discard(part_path.as_deref());
+                    log.info(format!("Image {} already exists", img_path.display()));
+                    duplicate(of, distance);
+                    return;
+                }
+            }
+        };
+        // 输出没有写入时撤销哈希记录，否则之后相似的图片都会被当作重复跳过
+        let rollback = || {
+            let undone = match (&replacing, &replaced_hash) {
+                (Some(claim), Some(old_hash)) => {
+                    let old = claim.entry.as_ref().unwrap();
+                    let old_meta = HashMeta {
+                        source: Some(old.source.clone()),
+                        output: old
+                            .output
+                            .strip_prefix(output_dir)
+                            .ok()
+                            .map(|path| path.display().to_string()),
+                        distance_threshold: options.distance_threshold,
+                    };
+                    context
+                        .replace_hash(id, old_hash.clone(), &old_meta)
+                        .map(|_| ())
+                }
+                _ => context.remove_hash(id),
+            };
+            if let Err(e) = undone {
+                log.error(format!(
+                    "Failed to remove hash of {} from {}: {}",
+                    img_path.display(),
+                    hashes_file_path.display(),
+                    e
+                ));
+            }
+        };
+        if let Some(part) = &part_path
+            && let Err(e) = retry_io(args.io_retries, || std::fs::rename(part, &output_path))
+        {
+            discard(Some(part));
+            rollback();
+            let message = format!("Failed to write {}: {}", output_path.display(), e);
+            if is_storage_full(&e) {
+                abort(message);
+            } else {
+                fail(Failure::Write, message);
+            }
+            return;
+        }
+        if let Some(archive) = &archive
+            && let Err(e) = retry_io(args.io_retries, || {
+                archive.append(&entry_name(&output_path), &img, mtime)
+            })
+        {
+            rollback();
+            abort(format!(
+                "Failed to write {} to {}: {}",
+                entry_name(&output_path),
+                args.archive.as_ref().unwrap().display(),
+                e
+            ));
+            return;
+        }
+        // 同名时旧的输出已被新写入的输出覆盖，不能再删除
+        if let Some(claim) = &mut replacing {
+            let (_, old) = claim.take();
+            if old.output != output_path {
+                let _ = std::fs::remove_file(&old.output);
+                if let Ok(relative) = old.output.strip_prefix(output_dir) {
+                    let _ = std::fs::remove_file(thumbnails_dir.join(relative));
+                }
+            }
+            log.info(format!(
+                "Image {} replaces lower quality {}",
+                img_path.display(),
+                old.output.display()
+            ));
+            summary.replaced.fetch_add(1, Ordering::Relaxed);
+        }
+        // 只为保留下来的图片生成缩略图
+        if let Some(thumbnail) = thumbnail {
+            let thumbnail_path = thumbnails_dir.join(&output);
+            let written = match &archive {
+                Some(archive) => retry_io(args.io_retries, || {
+                    archive.append(&entry_name(&thumbnail_path), &thumbnail, mtime)
+                }),
+                None => create_parent(&thumbnail_path)
+                    .and_then(|_| write_output(&thumbnail_path, &thumbnail, args.io_retries))
+                    .and_then(|_| mtime.map_or(Ok(()), |mtime| set_mtime(&thumbnail_path, mtime))),
+            };
+            if let Err(e) = written {
+                let message = format!(
+                    "Failed to write thumbnail {}: {}",
+                    thumbnail_path.display(),
+                    e
+                );
+                if is_storage_full(&e) {
+                    abort(message);
+                    return;
+                }
+                log.error(message);
+            } else {
+                output_total.fetch_add(thumbnail.len() as u64, Ordering::Relaxed);
+            }
+        }
+        let entry = ManifestEntry {
+            source,
+            output,
+            hash: hash.to_base64(),
+            source_bytes,
+            output_bytes: img.len() as u64,
+            source_pixels,
+            content_hash: content.filter(|_| args.exact_dedup),
+        };
+        if track_kept {
+            kept.lock().unwrap().insert(
+                id,
+                Kept {
+                    source: entry.source.clone(),
+                    output: output_path.clone(),
+                    pixels: source_pixels,
+                    bytes: source_bytes,
+                },
+            );
+        }
+        if let Some(content) = &entry.content_hash {
+            exact.lock().unwrap().insert(content.clone(), id);
+        }
+        if let Err(e) = append_line(
+            &mut manifest_file.lock().unwrap(),
+            &serde_json::to_string(&entry).unwrap(),
+        ) {
+            abort(format!("Failed to write {}: {}", MANIFEST_FILE_NAME, e));
+            return;
+        }
+        // 输出和记录都已写入，此时才处理源文件，下载的图片没有源文件
+        let local = matches!(data, SourceData::File(_));
+        if local && args.remove_source {
+            match std::fs::remove_file(img_path) {
+                Ok(()) => {
+                    log.info(format!("Removed source {}", img_path.display()));
+                    summary
+                        .sources_handled
+                        .lock()
+                        .unwrap()
+                        .push((img_path.to_path_buf(), None));
+                }
+                Err(e) => log.error(format!(
+                    "Failed to remove source {}: {}",
+                    img_path.display(),
+                    e
+                )),
+            }
+        } else if let Some(move_dir) = &args.move_source
+            && local
+        {
+            let target = Path::new(move_dir).join(relative_to_source(&source_dirs, img_path));
+            let moved = create_parent(&target)
+                .and_then(|_| reserve_output_path(&target))
+                .and_then(|target| {
+                    move_file(img_path, &target)
+                        .inspect_err(|_| {
+                            let _ = std::fs::remove_file(&target);
+                        })
+                        .map(|_| target)
+                });
+            match moved {
+                Ok(target) => {
+                    log.info(format!(
+                        "Moved source {} -> {}",
+                        img_path.display(),
+                        target.display()
+                    ));
+                    summary
+                        .sources_handled
+                        .lock()
+                        .unwrap()
+                        .push((img_path.to_path_buf(), Some(target)));
+                }
+                Err(e) => log.error(format!(
+                    "Failed to move source {}: {}",
+                    img_path.display(),
+                    e
+                )),
+            }
+        }
+        // 编码器会把高位深图片降为8位，提示用户
+        let max_bits = options.output_format.max_bits_per_channel();
+        if let Ok(bits) = data.bits_per_channel()
+            && bits > max_bits
+        {
+            log.info(format!(
+                "Image {} has {} bits per channel, reduced to {} in the output",
+                img_path.display(),
+                bits,
+                max_bits
+            ));
+            summary.reduced_depth.fetch_add(1, Ordering::Relaxed);
+        }
+        json.emit(&JsonRecord {
+            output: Some(output_path.display().to_string()),
+            hash: Some(entry.hash),
+            bytes_out: Some(img.len() as u64),
+            ..record("converted")
+        });
+        summary.converted.fetch_add(1, Ordering::Relaxed);
+        if args.verify_dedup {
+            written.lock().unwrap().push(output_path.clone());
+        }
+        summary.bytes_in.fetch_add(source_bytes, Ordering::Relaxed);
+        summary
+            .bytes_out
+            .fetch_add(img.len() as u64, Ordering::Relaxed);
+        let total = output_total.fetch_add(img.len() as u64, Ordering::Relaxed) + img.len() as u64;
+        if let Some(budget) = args.max_output_bytes
+            && total >= budget
+            && !budget_reached.swap(true, Ordering::SeqCst)
+        {
+            log.info(format!(
+                "Reached --max-output-bytes {}, finishing in-flight images",
+                HumanBytes(budget)
+            ));
+            STOP.store(true, Ordering::SeqCst);
+        }
+        log.debug(format!(
+            "Image {} converted in {:.2?}: {} -> {} ({}){}",
+            img_path.display(),
+            started.elapsed(),
+            HumanBytes(source_bytes),
+            HumanBytes(img.len() as u64),
+            size_change(source_bytes, img.len() as u64),
+            if args.target_size.is_some() {
+                format!(" at quality {}", quality)
+            } else {
+                String::new()
+            }
+        ));
+    };
+    images.par_iter().for_each(process);
+
+    let mut scanned = images.len();
+    if args.watch {
+        let interval = Duration::from_secs(args.watch_interval);
+        // 源目录有变化时才重新扫描，无法监听时退回到定时扫描
+        let (sender, events) = std::sync::mpsc::channel();
+        let watcher = watch_dirs(&source_dirs, sender)
+            .inspect_err(|e| {
+                pb.suspend(|| {
+                    eprintln!(
+                        "Warning: cannot watch the source directories, scanning every {} seconds instead: {}",
+                        args.watch_interval, e
+                    )
+                })
+            })
+            .ok();
+        // 已处理的图片及处理时的状态，同名文件被替换后会再次处理
+        let mut seen: HashMap<PathBuf, FileState> = images
+            .iter()
+            .filter_map(|source| match source {
+                Source::File(path) => Some((path.clone(), file_state(path)?)),
+                Source::Url(_) => None,
+            })
+            .collect();
+        // 上一次扫描时新出现的图片，状态不再变化才处理
+        let mut pending: HashMap<PathBuf, FileState> = HashMap::new();
+        // 开始监听前出现的图片由第一次扫描找到
+        let mut changed = true;
+        let mut last_scan = Instant::now();
+        pb.suspend(|| eprintln!("Watching for new images, press Ctrl-C to stop"));
+        while !STOP.load(Ordering::SeqCst) {
+            match events.recv_timeout(Duration::from_millis(100)) {
+                Ok(event) => changed |= is_source_change(&event, &find_options.skip_dirs),
+                Err(RecvTimeoutError::Timeout) => {}
+                // 没有监听时发送端已经丢弃，recv_timeout立即返回
+                Err(RecvTimeoutError::Disconnected) => {
+                    std::thread::sleep(Duration::from_millis(100))
+                }
+            }
+            // 有待确认的图片时即使没有新的变化也要再扫描一次
+            let due = watcher.is_none() || changed || !pending.is_empty();
+            if !due || last_scan.elapsed() < interval || STOP.load(Ordering::SeqCst) {
+                continue;
+            }
+            changed = false;
+            last_scan = Instant::now();
+            let mut ready = Vec::new();
+            let mut changing = HashMap::new();
+            for source_dir in &source_dirs {
+                for path in find_images_with(source_dir, &find_options, &ProgressBar::hidden()) {
+                    let Some(state) = file_state(&path).filter(|_| filter.matches(&path)) else {
+                        continue;
+                    };
+                    if seen.get(&path) == Some(&state) {
+                        continue;
+                    }
+                    if pending.get(&path) == Some(&state) {
+                        seen.insert(path.clone(), state);
+                        ready.push(path);
+                    } else {
+                        changing.insert(path, state);
+                    }
+                }
+            }
+            pending = changing;
+            if ready.is_empty() || STOP.load(Ordering::SeqCst) {
+                continue;
+            }
+            ready.sort();
+            ready.dedup();
+            scanned += ready.len();
+            pb.inc_length(ready.len() as u64);
+            ready
+                .into_par_iter()
+                .map(Source::File)
+                .for_each(|source| process(&source));
+            json.flush();
+            json_failed();
+            // 每批处理完就落盘，守护进程被强制结束时也不会丢失记录
+            let synced = context
+                .sync_hashes()
+                .and_then(|_| manifest_file.lock().unwrap().sync_all());
+            if let Err(e) = synced {
+                write_failed
+                    .lock()
+                    .unwrap()
+                    .get_or_insert(format!("Failed to save hashes or manifest: {}", e));
+                break;
+            }
+        }
+    }
+
+    json.flush();
+    json_failed();
+    // 确保已写入的哈希和清单落盘，下次运行能从这里继续
+    let mut write_failed = write_failed.into_inner().unwrap();
+    if let Err(e) = context.sync_hashes() {
+        write_failed.get_or_insert(format!("Failed to save hashes: {}", e));
+    }
+    if let Err(e) = manifest_file.into_inner().unwrap().sync_all() {
+        write_failed.get_or_insert(format!("Failed to write {}: {}", MANIFEST_FILE_NAME, e));
+    }
+    if let Some(archive) = archive {
+        // SQLite存储的最新记录可能还在WAL文件中，只打包文本存储
+        let mut extra = vec![(
+            MANIFEST_FILE_NAME.to_string(),
+            output_dir.join(MANIFEST_FILE_NAME),
+        )];
+        if args.hash.store == StoreKind::Flat && hashes_file_path.is_file() {
+            let name = hashes_file_path.file_name().unwrap().to_string_lossy();
+            extra.push((name.into_owned(), hashes_file_path.clone()));
+        }
+        let finished = extra
+            .iter()
+            .try_for_each(|(name, path)| archive.append(name, &std::fs::read(path)?, None))
+            .and_then(|_| archive.finish());
+        if let Err(e) = finished {
+            write_failed.get_or_insert(format!(
+                "Failed to write {}: {}",
+                args.archive.as_ref().unwrap().display(),
+                e
+            ));
+        }
+    }
+
+    if args.remove_source || args.move_source.is_some() {
+        check_output(summary.print_sources(&mut out, args.move_source.as_deref()));
+    }
+
+    // 报告写入失败时已转换的图片仍然有效，只在退出码中体现
+    let mut report_failed = false;
+    if let Some(report) = &args.report
+        && let Err(e) = write_report(
+            report,
+            &context,
+            &kept.into_inner().unwrap(),
+            duplicates.into_inner().unwrap(),
+        )
+    {
+        eprintln!("Error: Failed to write {}: {}", report.display(), e);
+        report_failed = true;
+    }
+
+    let print_summary = |out: &mut dyn Write| {
+        summary.print(out, scanned)?;
+        summary.print_failures(out, colors, verbosity == Verbosity::Verbose)
+    };
+    if let Some(message) = write_failed {
+        pb.abandon_with_message("Write failed");
+        check_output(print_summary(&mut out));
+        eprintln!(
+            "Error: stopped early because writing failed (disk full?): {}\n\
+             Images converted so far are recorded; free some space and rerun with --resume.",
+            message
+        );
+        std::process::exit(EXIT_FATAL);
+    }
+    if budget_reached.into_inner() {
+        pb.abandon_with_message("Output budget reached");
+        check_output(print_summary(&mut out));
+        check_output(writeln!(
+            out,
+            "Stopped after writing {} of the {} --max-output-bytes budget; \
+             rerun with --resume to convert the rest",
+            HumanBytes(output_total.into_inner()),
+            HumanBytes(args.max_output_bytes.unwrap())
+        ));
+    } else if STOP.load(Ordering::SeqCst) {
+        pb.abandon_with_message("Interrupted");
+        check_output(print_summary(&mut out));
+        if args.histogram {
+            check_output(histogram.print(&mut out));
+        }
+        check_output(timings.print(&mut out));
+        std::process::exit(EXIT_INTERRUPTED);
+    } else {
+        pb.finish_with_message("Processing complete");
+        check_output(print_summary(&mut out));
+    }
+    if args.histogram {
+        check_output(histogram.print(&mut out));
+    }
+    check_output(timings.print(&mut out));
+    let mut slipped = 0;
+    if args.verify_dedup {
+        let written = written.into_inner().unwrap();
+        let checked = written.len();
+        for (earlier, later, distance) in
+            find_near_duplicates(&context, written, distance_threshold)
+        {
+            eprintln!(
+                "Warning: outputs {} and {} are within distance {}",
+                earlier.display(),
+                later.display(),
+                distance
+            );
+            slipped += 1;
+        }
+        if slipped == 0 {
+            check_output(writeln!(
+                out,
+                "Verified {} outputs: no near-duplicates",
+                checked
+            ));
+        } else {
+            check_output(writeln!(
+                out,
+                "Verified {} outputs: {} near-duplicate pairs slipped through",
+                checked, slipped
+            ));
+        }
+    }
+    if summary.errors.load(Ordering::Relaxed) > 0 || slipped > 0 || report_failed {
+        check_output(out.flush());
+        std::process::exit(EXIT_ERRORS);
+    }
+}
+
+// 统计输出目录中各格式的图片数量和大小，以及清单和哈希存储的概况
+fn stats(args: StatsArgs) {
+    check_output(write_stats(args, &mut std::io::stdout().lock()));
+}
+
+fn write_stats(args: StatsArgs, out: &mut dyn Write) -> std::io::Result<()> {
+    let output_dir = args.output_dir.as_path();
+    if !output_dir.is_dir() {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!("Output directory {} does not exist", output_dir.display()),
+            )
+            .exit();
+    }
+    let find_options = FindOptions {
+        formats: OutputFormat::value_variants()
+            .iter()
+            .map(|f| f.extension().to_string())
+            .collect(),
+        ..FindOptions::default()
+    };
+    let duplicates_dir = output_dir.join(DUPLICATES_DIR_NAME);
+    let thumbnails_dir = output_dir.join(THUMBNAILS_DIR_NAME);
+    let mut by_format: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    let (mut moved, mut moved_bytes) = (0, 0);
+    let (mut thumbnails, mut thumbnail_bytes) = (0, 0);
+    for path in find_images_with(output_dir, &find_options, &ProgressBar::hidden()) {
+        let bytes = std::fs::metadata(&path).map_or(0, |m| m.len());
+        if path.starts_with(&duplicates_dir) {
+            moved += 1;
+            moved_bytes += bytes;
+            continue;
+        }
+        if path.starts_with(&thumbnails_dir) {
+            thumbnails += 1;
+            thumbnail_bytes += bytes;
+            continue;
+        }
+        let extension = path.extension().unwrap().to_string_lossy().to_lowercase();
+        let entry = by_format.entry(extension).or_default();
+        entry.0 += 1;
+        entry.1 += bytes;
+    }
+
+    writeln!(out, "Output directory {}", output_dir.display())?;
+    for (extension, (count, bytes)) in &by_format {
+        writeln!(
+            out,
+            "  {}: {} images, {}",
+            extension,
+            count,
+            HumanBytes(*bytes)
+        )?;
+    }
+    writeln!(
+        out,
+        "  Total: {} images, {}",
+        by_format.values().map(|v| v.0).sum::<u64>(),
+        HumanBytes(by_format.values().map(|v| v.1).sum())
+    )?;
+    if thumbnails > 0 {
+        writeln!(
+            out,
+            "  {} thumbnails, {}",
+            thumbnails,
+            HumanBytes(thumbnail_bytes)
+        )?;
+    }
+    if moved > 0 {
+        writeln!(
+            out,
+            "  {} duplicates moved to {}, {}",
+            moved,
+            duplicates_dir.display(),
+            HumanBytes(moved_bytes)
+        )?;
+    }
+    let manifest = read_manifest(output_dir);
+    if !manifest.is_empty() {
+        let source_bytes: u64 = manifest.iter().map(|e| e.source_bytes).sum();
+        let output_bytes: u64 = manifest.iter().map(|e| e.output_bytes).sum();
+        writeln!(
+            out,
+            "  Manifest: {} conversions, {} into {} ({:.1}% of the original size)",
+            manifest.len(),
+            HumanBytes(source_bytes),
+            HumanBytes(output_bytes),
+            output_bytes as f64 / source_bytes.max(1) as f64 * 100.0
+        )?;
+    }
+
+    let hashes_file_path = args
+        .hashes_file_path
+        .unwrap_or_else(|| PathBuf::from(args.store.file_name()));
+    let path = hashes_file_path.as_path();
+    match store_info(args.store, path) {
+        Ok(Some(info)) => {
+            writeln!(
+                out,
+                "Hash store {}: {} hashes, {}",
+                path.display(),
+                info.hashes,
+                HumanBytes(std::fs::metadata(path).map_or(0, |m| m.len()))
+            )?;
+            if let Some(header) = info.header {
+                writeln!(out, "  {}", header.trim_start_matches('#').trim())?;
+            }
+        }
+        Ok(None) => writeln!(out, "Hash store {}: not found", path.display())?,
+        Err(e) => writeln!(out, "Hash store {}: {}", path.display(), e)?,
+    }
+    Ok(())
+}
+
+// 判重的结果，解码后的图片留给转换使用
+struct LookedUp {
+    decoded: Decoded,
+    hashes: ImageHashes,
+    decision: HashDecision,
+    // 只在详细模式下计算
+    nearest: Option<u32>,
+}
+
+// 解码图片并查找相似图片，详细模式下额外计算最近的距离
+fn lookup(
+    context: &Context,
+    data: &SourceData,
+    distance_threshold: u32,
+    verbose: bool,
+) -> Result<LookedUp, ImageError> {
+    let decoded = data.decode(context)?;
+    let hashes = context.hash_decoded(&decoded);
+    Ok(LookedUp {
+        decision: context.compare(&hashes, distance_threshold),
+        nearest: verbose.then(|| context.nearest_distance(&hashes)).flatten(),
+        decoded,
+        hashes,
+    })
+}
+
+// with_timeout没有得到结果的原因
+#[derive(Debug, PartialEq)]
+enum Unfinished {
+    TimedOut,
+    Panicked,
+}
+
+// 在单独的线程上执行f，线程无法取消，超时后会在后台继续运行到结束。
+// f panic时返回错误，只让这一张图片失败，不影响其他图片
+fn with_timeout<T: Send + 'static>(
+    timeout: Option<Duration>,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, Unfinished> {
+    let Some(timeout) = timeout else {
+        return std::panic::catch_unwind(std::panic::AssertUnwindSafe(f))
+            .map_err(|_| Unfinished::Panicked);
+    };
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(f());
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(value) => Ok(value),
+        Err(RecvTimeoutError::Timeout) => Err(Unfinished::TimedOut),
+        // 发送端在没有发送结果时被丢弃，说明f panic了
+        Err(RecvTimeoutError::Disconnected) => Err(Unfinished::Panicked),
+    }
+}
+
+// --name-template解析后的片段
+#[derive(Clone)]
+enum NamePart {
+    Literal(String),
+    Uuid,
+    Uuid4,
+    Stem,
+    Date,
+    Index,
+}
+
+#[derive(Clone)]
+struct NameTemplate(Vec<NamePart>);
+
+impl std::str::FromStr for NameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let path = Path::new(s);
+        if s.is_empty()
+            || path.has_root()
+            || path
+                .components()
+                .any(|c| !matches!(c, std::path::Component::Normal(_)))
+        {
+            return Err(format!(
+                "'{}' must be a relative path without '.' or '..' components",
+                s
+            ));
+        }
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(NamePart::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed '{{' in '{}'", s))?;
+            parts.push(match &rest[start + 1..start + end] {
+                "uuid" => NamePart::Uuid,
+                "uuid4" => NamePart::Uuid4,
+                "stem" => NamePart::Stem,
+                "date" => NamePart::Date,
+                "index" => NamePart::Index,
+                other => {
+                    return Err(format!(
+                        "unknown placeholder {{{}}}, expected {{uuid}}, {{uuid4}}, {{stem}}, \
+                         {{date}} or {{index}}",
+                        other
+                    ));
+                }
+            });
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(NamePart::Literal(rest.to_string()));
+        }
+        Ok(NameTemplate(parts))
+    }
+}
+
+impl NameTemplate {
+    // 生成不含扩展名的输出路径
+    fn render(&self, img_path: &Path, index: u64) -> String {
+        let mut name = String::new();
+        for part in &self.0 {
+            match part {
+                NamePart::Literal(text) => name.push_str(text),
+                NamePart::Uuid => name.push_str(&uuid::Uuid::now_v7().to_string()),
+                NamePart::Uuid4 => name.push_str(&uuid::Uuid::new_v4().to_string()),
+                NamePart::Stem => {
+                    name.push_str(&img_path.file_stem().unwrap_or_default().to_string_lossy())
+                }
+                NamePart::Date => name.push_str(&utc_date(SystemTime::now())),
+                NamePart::Index => name.push_str(&index.to_string()),
+            }
+        }
+        name
+    }
+}
+
+// UTC日期，格式为YYYY-MM-DD
+fn utc_date(time: SystemTime) -> String {
+    OffsetDateTime::from(time)
+        .date()
+        .format(format_description!("[year]-[month]-[day]"))
+        .unwrap()
+}
+
+// 解析RFC 3339时间，只有日期时取UTC零点
+fn parse_rfc3339(s: &str) -> Option<SystemTime> {
+    let parsed = match OffsetDateTime::parse(s, &Rfc3339) {
+        Ok(parsed) => parsed,
+        Err(_) => Date::parse(s, format_description!("[year]-[month]-[day]"))
+            .ok()?
+            .midnight()
+            .assume_utc(),
+    };
+    Some(parsed.into())
+}
+
+// 在文件所在目录和文件名之间插入`levels`层子目录，每层取文件名的blake3哈希中的两位十六进制数字
+fn sharded(path: &Path, levels: u8) -> PathBuf {
+    let name = path.file_name().unwrap();
+    let digest = blake3::hash(name.as_encoded_bytes()).to_hex();
+    let mut sharded = path.parent().unwrap().to_path_buf();
+    for level in 0..levels as usize {
+        sharded.push(&digest[2 * level..2 * level + 2]);
+    }
+    sharded.join(name)
+}
+
+// 创建输出所在的目录，错误信息带上目录
+fn create_parent(path: &Path) -> std::io::Result<()> {
+    let parent = path.parent().unwrap();
+    std::fs::create_dir_all(parent).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("Failed to create {}: {}", parent.display(), e),
+        )
+    })
+}
+
+// 按换行或NUL分隔的路径列表，去掉每项首尾的空白并跳过空项，unix上路径不必是UTF-8
+fn list_paths(content: &[u8]) -> Vec<PathBuf> {
+    content
+        .split(|&b| b == b'\n' || b == b'\0')
+        .map(|item| item.trim_ascii())
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            #[cfg(unix)]
+            {
+                use std::os::unix::ffi::OsStrExt;
+                PathBuf::from(std::ffi::OsStr::from_bytes(item))
+            }
+            #[cfg(not(unix))]
+            {
+                PathBuf::from(String::from_utf8_lossy(item).into_owned())
+            }
+        })
+        .collect()
+}
+
+// 图片相对于所在源目录的路径，不在任何源目录下的图片（来自--from-file或--stdin）只取文件名
+fn relative_to_source<'a>(source_dirs: &[&Path], img_path: &'a Path) -> &'a Path {
+    match source_dirs.iter().find(|dir| img_path.starts_with(dir)) {
+        Some(source_dir) => img_path.strip_prefix(source_dir).unwrap(),
+        None => Path::new(img_path.file_name().unwrap()),
+    }
+}
+
+// 输出相对源文件的大小变化，例如"87% smaller"
+fn size_change(source: u64, output: u64) -> String {
+    if source == 0 {
+        return "n/a".to_string();
+    }
+    let percent = (output as f64 / source as f64 - 1.0) * 100.0;
+    if percent <= 0.0 {
+        format!("{:.0}% smaller", percent.abs())
+    } else {
+        format!("{:.0}% larger", percent)
+    }
+}
+
+fn format_distance(distance: Option<u32>) -> String {
+    distance.map_or_else(|| "n/a".to_string(), |d| d.to_string())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+// 修改已写入文件的修改时间
+fn set_mtime(path: &Path, mtime: SystemTime) -> std::io::Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(mtime)
+}
+
+// 进度的显示方式
+struct Progress<'a> {
+    template: Option<&'a str>,
+    // 不画进度条，定期输出一行进度
+    plain: bool,
+}
+
+// plain模式下输出进度的间隔
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+impl Progress<'_> {
+    // plain模式下隐藏进度条，在后台线程中定期输出进度，直到进度条结束
+    fn start(&self, pb: &ProgressBar, verbosity: Verbosity) {
+        if !self.plain {
+            return;
+        }
+        pb.set_draw_target(ProgressDrawTarget::hidden());
+        if verbosity == Verbosity::Quiet {
+            return;
+        }
+        let pb = pb.clone();
+        std::thread::spawn(move || {
+            loop {
+                std::thread::sleep(STATUS_INTERVAL);
+                if pb.is_finished() {
+                    break;
+                }
+                eprintln!(
+                    "Processed {}/{} images in {}",
+                    pb.position(),
+                    pb.length().unwrap_or(0),
+                    HumanDuration(pb.elapsed())
+                );
+            }
+        });
+    }
+}
+
+// 按日志级别通过进度条输出，保证信息不会打乱进度条。进度条隐藏时直接输出到stderr
+struct Log<'a> {
+    pb: &'a ProgressBar,
+    verbosity: Verbosity,
+}
+
+impl Log<'_> {
+    fn println(&self, message: String) {
+        if self.pb.is_hidden() {
+            eprintln!("{}", message);
+        } else {
+            self.pb.println(message);
+        }
+    }
+
+    fn error(&self, message: String) {
+        self.println(message);
+    }
+
+    fn info(&self, message: String) {
+        if self.verbosity >= Verbosity::Normal {
+            self.println(message);
+        }
+    }
+
+    fn debug(&self, message: String) {
+        if self.verbosity >= Verbosity::Verbose {
+            self.println(message);
+        }
+    }
+}
+
+// --json输出的一行
+#[derive(Serialize, Default)]
+struct JsonRecord {
+    source: String,
+    // converted、duplicate、existing、too_small、animated或error
+    status: &'static str,
+    output: Option<String>,
+    hash: Option<String>,
+    // 重复图片匹配到的已保留哈希
+    matched: Option<String>,
+    distance: Option<u32>,
+    bytes_in: u64,
+    bytes_out: Option<u64>,
+    error: Option<String>,
+}
+
+// 未指定--json时不输出
+struct JsonLines {
+    writer: Option<Mutex<Box<dyn Write + Send>>>,
+    // 第一个写入错误，之后不再写入，由调用方决定是否停止
+    failed: Mutex<Option<std::io::Error>>,
+}
+
+impl JsonLines {
+    fn new(writer: Option<Box<dyn Write + Send>>) -> Self {
+        JsonLines {
+            writer: writer.map(Mutex::new),
+            failed: Mutex::new(None),
+        }
+    }
+
+    fn emit(&self, record: &JsonRecord) {
+        self.write(|writer| writeln!(writer, "{}", serde_json::to_string(record).unwrap()));
+    }
+
+    fn flush(&self) {
+        self.write(|writer| writer.flush());
+    }
+
+    fn write(&self, f: impl FnOnce(&mut Box<dyn Write + Send>) -> std::io::Result<()>) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        let mut failed = self.failed.lock().unwrap();
+        if failed.is_none()
+            && let Err(e) = f(&mut writer.lock().unwrap())
+        {
+            *failed = Some(e);
+        }
+    }
+
+    // 取出写入错误，只报告一次
+    fn take_error(&self) -> Option<std::io::Error> {
+        self.failed.lock().unwrap().take()
+    }
+}
+
+// 离开作用域时推进进度条，处理过程中从任何位置返回都会计数
+struct Tick<'a> {
+    pb: &'a ProgressBar,
+    done_bytes: &'a AtomicU64,
+    bytes: u64,
+}
+
+impl Drop for Tick<'_> {
+    fn drop(&mut self) {
+        self.done_bytes.fetch_add(self.bytes, Ordering::Relaxed);
+        self.pb.inc(1);
+    }
+}
+
+// 要处理的图片，本地文件排在--url-list的URL之前
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Source {
+    File(PathBuf),
+    Url(String),
+}
+
+impl Source {
+    // 日志、清单和失败汇总中代表这张图片的路径，URL原样使用
+    fn path(&self) -> &Path {
+        match self {
+            Source::File(path) => path,
+            Source::Url(url) => Path::new(url),
+        }
+    }
+}
+
+// 图片的内容，本地文件按需读取，下载的图片保存在内存中
+#[derive(Clone)]
+enum SourceData {
+    File(PathBuf),
+    Memory(Arc<[u8]>),
+}
+
+impl SourceData {
+    fn dimensions(&self) -> Result<(u32, u32), ImageError> {
+        match self {
+            SourceData::File(path) => image::image_dimensions(path),
+            SourceData::Memory(data) => image::ImageReader::new(std::io::Cursor::new(&data[..]))
+                .with_guessed_format()?
+                .into_dimensions(),
+        }
+    }
+
+    fn pixels(&self) -> Option<u64> {
+        self.dimensions()
+            .ok()
+            .map(|(width, height)| width as u64 * height as u64)
+    }
+
+    fn is_animated(&self) -> Result<bool, ImageError> {
+        match self {
+            SourceData::File(path) => is_animated(path),
+            SourceData::Memory(data) => is_animated_bytes(data),
+        }
+    }
+
+    fn content_hash(&self, retries: u32) -> std::io::Result<String> {
+        match self {
+            SourceData::File(path) => content_hash(path, retries),
+            SourceData::Memory(data) => Ok(content_hash_bytes(data)),
+        }
+    }
+
+    fn bits_per_channel(&self) -> Result<u16, ImageError> {
+        match self {
+            SourceData::File(path) => source_bits_per_channel(path),
+            SourceData::Memory(data) => source_bits_per_channel_bytes(data),
+        }
+    }
+
+    fn decode(&self, context: &Context) -> Result<Decoded, ImageError> {
+        match self {
+            SourceData::File(path) => context.decode(path),
+            SourceData::Memory(data) => context.decode_bytes(data),
+        }
+    }
+}
+
+// 用种子确定地随机保留n张图片，保持原来的顺序
+fn sample<T: Ord>(images: &mut Vec<T>, n: usize, seed: u64) {
+    if n >= images.len() {
+        return;
+    }
+    // splitmix64，不需要密码学强度，只要同一个种子得到同样的结果
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+    // 部分Fisher-Yates洗牌，只打乱前n个位置
+    for i in 0..n {
+        let j = i + (next() % (images.len() - i) as u64) as usize;
+        images.swap(i, j);
+    }
+    images.truncate(n);
+    images.sort();
+}
+
+// 递归监听所有源目录，事件发送到`sender`
+fn watch_dirs(
+    dirs: &[&Path],
+    sender: std::sync::mpsc::Sender<notify::Result<notify::Event>>,
+) -> notify::Result<notify::RecommendedWatcher> {
+    let mut watcher = notify::recommended_watcher(sender)?;
+    for dir in dirs {
+        // 与skip_dirs一样使用规范化路径，事件中的路径才能与它比较
+        let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+        watcher.watch(&dir, notify::RecursiveMode::Recursive)?;
+    }
+    Ok(watcher)
+}
+
+// 读取文件和跳过的目录中的变化不会带来新图片。事件出错或队列溢出时可能漏掉了变化，重新扫描一次
+fn is_source_change(event: &notify::Result<notify::Event>, skip_dirs: &[PathBuf]) -> bool {
+    match event {
+        Ok(event) => {
+            !event.kind.is_access()
+                && (event.paths.is_empty()
+                    || event
+                        .paths
+                        .iter()
+                        .any(|path| !skip_dirs.iter().any(|dir| path.starts_with(dir))))
+        }
+        Err(_) => true,
+    }
+}
+
+// --watch判断文件是否写完用到的大小和修改时间
+type FileState = (u64, SystemTime);
+
+fn file_state(path: &Path) -> Option<FileState> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+// --min-width和--min-height
+struct MinSize {
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+impl MinSize {
+    // 图片尺寸小于下限时返回其尺寸，读取不到尺寸时交给后续解码报错
+    fn rejects(&self, data: &SourceData) -> Option<(u32, u32)> {
+        if self.width.is_none() && self.height.is_none() {
+            return None;
+        }
+        let (width, height) = data.dimensions().ok()?;
+        let too_small = self.width.is_some_and(|min| width < min)
+            || self.height.is_some_and(|min| height < min);
+        too_small.then_some((width, height))
+    }
+}
+
+// 已保留图片的源文件和输出，以及源图片的分辨率和大小
+struct Kept {
+    source: String,
+    output: PathBuf,
+    pixels: Option<u64>,
+    bytes: u64,
+}
+
+impl Kept {
+    fn from_entry(output_dir: &Path, entry: &ManifestEntry) -> Self {
+        Kept {
+            source: entry.source.clone(),
+            output: output_dir.join(&entry.output),
+            pixels: entry.source_pixels,
+            bytes: entry.source_bytes,
+        }
+    }
+}
+
+// 取代已保留图片前先从表中取出它，其他线程不会同时取代同一张图片；处理失败时放回
+struct Claim<'a> {
+    kept: &'a Mutex<HashMap<usize, Kept>>,
+    id: usize,
+    entry: Option<Kept>,
+}
+
+impl<'a> Claim<'a> {
+    // 两边都有分辨率且不同时比较分辨率，否则比较文件大小
+    fn if_better(
+        kept: &'a Mutex<HashMap<usize, Kept>>,
+        of: usize,
+        pixels: Option<u64>,
+        bytes: u64,
+    ) -> Option<Self> {
+        let mut table = kept.lock().unwrap();
+        let old = table.get(&of)?;
+        let better = match (pixels, old.pixels) {
+            (Some(new), Some(old)) if new != old => new > old,
+            _ => bytes > old.bytes,
+        };
+        if !better {
+            return None;
+        }
+        Some(Claim {
+            kept,
+            id: of,
+            entry: table.remove(&of),
+        })
+    }
+
+    fn take(&mut self) -> (usize, Kept) {
+        (self.id, self.entry.take().unwrap())
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            self.kept.lock().unwrap().insert(self.id, entry);
+        }
+    }
+}
+
+// 转换过程中的统计
+#[derive(Default)]
+struct Summary {
+    converted: AtomicU64,
+    duplicates: AtomicU64,
+    replaced: AtomicU64,
+    reduced_depth: AtomicU64,
+    existing: AtomicU64,
+    too_small: AtomicU64,
+    animated: AtomicU64,
+    // 删除或移走的源文件及移动后的路径，删除时为None
+    sources_handled: Mutex<Vec<(PathBuf, Option<PathBuf>)>>,
+    errors: AtomicU64,
+    // 出错的图片及出错的类别，结束时汇总
+    failures: Mutex<Vec<(Failure, PathBuf)>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+// 图片处理失败的类别
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Failure {
+    Read,
+    Fetch,
+    Decode,
+    Encode,
+    Timeout,
+    Animated,
+    Exists,
+    Write,
+}
+
+impl Failure {
+    // 单数和复数形式的名称
+    fn names(self) -> (&'static str, &'static str) {
+        match self {
+            Failure::Read => ("read failure", "read failures"),
+            Failure::Fetch => ("fetch failure", "fetch failures"),
+            Failure::Decode => ("decode failure", "decode failures"),
+            Failure::Encode => ("encode failure", "encode failures"),
+            Failure::Timeout => ("timeout", "timeouts"),
+            Failure::Animated => ("animated image", "animated images"),
+            Failure::Exists => ("existing output", "existing outputs"),
+            Failure::Write => ("write failure", "write failures"),
+        }
+    }
+}
+
+impl Summary {
+    // 按类别汇总错误，例如"Errors: 12 decode failures, 1 timeout"，list_paths时逐个列出路径
+    fn print_failures(
+        &self,
+        out: &mut dyn Write,
+        colors: bool,
+        list_paths: bool,
+    ) -> std::io::Result<()> {
+        let mut failures = self.failures.lock().unwrap();
+        if failures.is_empty() {
+            return Ok(());
+        }
+        failures.sort();
+        let mut groups: Vec<(Failure, usize)> = Vec::new();
+        for (failure, _) in failures.iter() {
+            match groups.last_mut() {
+                Some((last, count)) if last == failure => *count += 1,
+                _ => groups.push((*failure, 1)),
+            }
+        }
+        let groups: Vec<String> = groups
+            .into_iter()
+            .map(|(failure, count)| {
+                let (singular, plural) = failure.names();
+                format!(
+                    "{} {}",
+                    console::style(count).red().bold().force_styling(colors),
+                    if count == 1 { singular } else { plural }
+                )
+            })
+            .collect();
+        writeln!(out, "Errors: {}", groups.join(", "))?;
+        if list_paths {
+            for (failure, path) in failures.iter() {
+                writeln!(out, "  {}: {}", failure.names().0, path.display())?;
+            }
+        }
+        Ok(())
+    }
+
+    // 删除或移走的源文件数量，并逐个列出路径，方便事后核对或找回
+    fn print_sources(&self, out: &mut dyn Write, move_dir: Option<&Path>) -> std::io::Result<()> {
+        let mut handled = self.sources_handled.lock().unwrap();
+        handled.sort();
+        match move_dir {
+            Some(move_dir) => writeln!(
+                out,
+                "Moved {} source images to {}",
+                handled.len(),
+                move_dir.display()
+            )?,
+            None => writeln!(out, "Removed {} source images", handled.len())?,
+        }
+        for (source, target) in handled.iter() {
+            match target {
+                Some(target) => writeln!(out, "  {} -> {}", source.display(), target.display())?,
+                None => writeln!(out, "  {}", source.display())?,
+            }
+        }
+        Ok(())
+    }
+
+    fn print(&self, out: &mut dyn Write, scanned: usize) -> std::io::Result<()> {
+        let bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        let bytes_out = self.bytes_out.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "Scanned {} images: {} converted, {} duplicates skipped, {} errors",
+            scanned,
+            self.converted.load(Ordering::Relaxed),
+            self.duplicates.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed)
+        )?;
+        let existing = self.existing.load(Ordering::Relaxed);
+        if existing > 0 {
+            writeln!(out, "{} images were already converted", existing)?;
+        }
+        let too_small = self.too_small.load(Ordering::Relaxed);
+        if too_small > 0 {
+            writeln!(
+                out,
+                "{} images were smaller than --min-width/--min-height",
+                too_small
+            )?;
+        }
+        let animated = self.animated.load(Ordering::Relaxed);
+        if animated > 0 {
+            writeln!(out, "{} animated images were skipped", animated)?;
+        }
+        let replaced = self.replaced.load(Ordering::Relaxed);
+        if replaced > 0 {
+            writeln!(
+                out,
+                "{} kept images were replaced by higher quality duplicates",
+                replaced
+            )?;
+        }
+        let reduced_depth = self.reduced_depth.load(Ordering::Relaxed);
+        if reduced_depth > 0 {
+            writeln!(
+                out,
+                "{} high bit depth images were reduced to 8 bits per channel, \
+                 use --output-format png to keep them",
+                reduced_depth
+            )?;
+        }
+        if bytes_in > 0 {
+            writeln!(
+                out,
+                "Converted {} into {} ({:.1}% of the original size)",
+                HumanBytes(bytes_in),
+                HumanBytes(bytes_out),
+                bytes_out as f64 / bytes_in as f64 * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// --timings统计的阶段
+#[derive(Clone, Copy)]
+enum Stage {
+    Discovery,
+    Hashing,
+    EncodeWait,
+    Encoding,
+    Writing,
+}
+
+const STAGES: [(Stage, &str); 5] = [
+    (Stage::Discovery, "discovery"),
+    (Stage::Hashing, "hashing"),
+    (Stage::EncodeWait, "waiting for an encode slot"),
+    (Stage::Encoding, "encoding"),
+    (Stage::Writing, "writing"),
+];
+
+// 各阶段累计的耗时（纳秒），未开启时start不计时
+struct Timings {
+    enabled: bool,
+    started: Instant,
+    stages: [AtomicU64; STAGES.len()],
+}
+
+// 离开作用域时把经过的时间加到对应阶段，提前返回的图片也会计入
+struct StageTimer<'a> {
+    total: &'a AtomicU64,
+    started: Instant,
+}
+
+impl Drop for StageTimer<'_> {
+    fn drop(&mut self) {
+        self.total
+            .fetch_add(self.started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Timings {
+    fn new(enabled: bool) -> Self {
+        Timings {
+            enabled,
+            started: Instant::now(),
+            stages: Default::default(),
+        }
+    }
+
+    fn start(&self, stage: Stage) -> Option<StageTimer<'_>> {
+        self.enabled.then(|| StageTimer {
+            total: &self.stages[stage as usize],
+            started: Instant::now(),
+        })
+    }
+
+    fn print(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        writeln!(
+            out,
+            "Timings (wall time {:.2?}, per-image stages summed over threads):",
+            self.started.elapsed()
+        )?;
+        for (stage, name) in STAGES {
+            let nanos = self.stages[stage as usize].load(Ordering::Relaxed);
+            writeln!(out, "  {:<27} {:.2?}", name, Duration::from_nanos(nanos))?;
+        }
+        Ok(())
+    }
+}
+
+// 直方图低处各桶的上界（含），判重阈值通常落在这个范围内
+const HISTOGRAM_LOW_BOUNDS: [u32; 7] = [2, 5, 9, 15, 24, 40, 64];
+
+// 每张图片与最相似的已存哈希之间的距离分布，用于选择合适的判重阈值
+struct Histogram {
+    // 各桶的上界（含），超过最后一个上界的距离归入最后一个桶
+    bounds: Vec<u32>,
+    buckets: Vec<AtomicU64>,
+    // 查找时还没有任何已存哈希的图片
+    first: AtomicU64,
+}
+
+impl Histogram {
+    // 低处的桶固定，之后每个桶的上界翻倍，直到哈希的位数，大哈希的远距离也能分开
+    fn new(bits: u32) -> Self {
+        let mut bounds: Vec<u32> = HISTOGRAM_LOW_BOUNDS
+            .into_iter()
+            .take_while(|&bound| bound < bits)
+            .collect();
+        while bounds.last().is_none_or(|&last| last < bits) {
+            let next = bounds.last().map_or(bits, |&last| last.saturating_mul(2));
+            bounds.push(next.min(bits));
+        }
+        Histogram {
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            bounds,
+            first: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, nearest: Option<u32>) {
+        let Some(distance) = nearest else {
+            self.first.fetch_add(1, Ordering::Relaxed);
+            return;
+        };
+        let index = self
+            .bounds
+            .iter()
+            .position(|&bound| distance <= bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn print(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        let max = counts.iter().copied().max().unwrap_or(0);
+        writeln!(out, "Nearest stored hash distance:")?;
+        // 只打印到最后一个非空的桶，长尾的空桶没有意义
+        let last = counts.iter().rposition(|&c| c > 0).map_or(0, |i| i + 1);
+        for (i, &count) in counts[..last].iter().enumerate() {
+            let bounds = &self.bounds;
+            let label = match i {
+                0 => format!("0-{}", bounds[0]),
+                i if i == bounds.len() => format!("{}+", bounds[i - 1] + 1),
+                i => format!("{}-{}", bounds[i - 1] + 1, bounds[i]),
+            };
+            let bar = "#".repeat((count * 40).div_ceil(max) as usize);
+            let line = format!("  {:>8} {:>7} {}", label, count, bar);
+            writeln!(out, "{}", line.trim_end())?;
+        }
+        let first = self.first.load(Ordering::Relaxed);
+        if first > 0 {
+            writeln!(out, "  {} images had no stored hash to compare with", first)?;
+        }
+        Ok(())
+    }
+}
+
+const BENCHMARK_IMAGES: u32 = 16;
+
+// 生成样例图片，分别统计哈希、编码和完整流程的吞吐量
+fn benchmark(context: &Context, options: &ConvertOptions) -> Result<(), ImageError> {
+    let dir = std::env::temp_dir().join(format!("convert_img_bench_{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let result = run_benchmark(context, options, &dir, &mut std::io::stdout());
+    // 出错时也删除样例图片
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+fn run_benchmark(
+    context: &Context,
+    options: &ConvertOptions,
+    dir: &Path,
+    out: &mut dyn Write,
+) -> Result<(), ImageError> {
+    // 渐变叠加噪点，尺寸和内容各不相同，结果可重复
+    let samples: Vec<PathBuf> = (0..BENCHMARK_IMAGES)
+        .map(|i| {
+            let (width, height) = (96 + i * 8, 64 + i * 8);
+            let img = image::RgbImage::from_fn(width, height, |x, y| {
+                let noise = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663) ^ i) % 32;
+                image::Rgb([
+                    ((x * 255 / width) as u8).wrapping_add(noise as u8),
+                    ((y * 255 / height) as u8).wrapping_add((i * 10) as u8),
+                    (((x + y) * 128 / (width + height)) as u8).wrapping_add(noise as u8),
+                ])
+            });
+            let path = dir.join(format!("{}.png", i));
+            img.save(&path)?;
+            Ok(path)
+        })
+        .collect::<Result<_, ImageError>>()?;
+    let decoded = samples
+        .iter()
+        .map(image::open)
+        .collect::<Result<Vec<_>, _>>()?;
+    let count = samples.len() as f64;
+    let mut report = |stage: &str, start: Instant| {
+        let secs = start.elapsed().as_secs_f64();
+        writeln!(
+            out,
+            "{:<10} {:>8.1} images/sec ({:.2}s)",
+            stage,
+            count / secs,
+            secs
+        )
+    };
+
+    let start = Instant::now();
+    samples
+        .par_iter()
+        .try_for_each(|path| context.hash_image(path).map(drop))?;
+    report("hash", start)?;
+
+    let start = Instant::now();
+    decoded.par_iter().try_for_each(|img| {
+        options
+            .output_format
+            .encode(img, options.quality, options.speed, &Metadata::default())
+            .map(drop)
+    })?;
+    report("encode", start)?;
+
+    let start = Instant::now();
+    let ratios = samples
+        .par_iter()
+        .map(|path| {
+            context.hash_image(path)?;
+            let output = context.convert_one(path, options)?.data;
+            Ok(output.len() as f64 / std::fs::metadata(path)?.len() as f64)
+        })
+        .collect::<Result<Vec<f64>, ImageError>>()?;
+    report("pipeline", start)?;
+    writeln!(
+        out,
+        "Average output size: {:.1}% of the source",
+        ratios.iter().sum::<f64>() / count * 100.0
+    )?;
+    Ok(())
+}
+
+// 只计算哈希并报告将要执行的操作，不转换也不写入任何文件，返回出错的图片数量
+#[allow(clippy::too_many_arguments)]
+fn dry_run(
+    context: &Context,
+    images: &[PathBuf],
+    options: &ConvertOptions,
+    verbosity: Verbosity,
+    progress: &Progress,
+    min_size: &MinSize,
+    animated: AnimatedPolicy,
+    histogram: Option<&Histogram>,
+) -> u64 {
+    let pb = init_pb(images.len(), progress.template);
+    progress.start(&pb, verbosity);
+    let log = Log { pb: &pb, verbosity };
+    let errors = AtomicU64::new(0);
+    images.par_iter().for_each(|img_path| {
+        let data = SourceData::File(img_path.clone());
+        if min_size.rejects(&data).is_some() {
+            log.info(format!("Would skip too small {}", img_path.display()));
+            pb.inc(1);
+            return;
+        }
+        if animated != AnimatedPolicy::FirstFrame && is_animated(img_path).unwrap_or(false) {
+            if animated == AnimatedPolicy::Error {
+                log.error(format!("Image {} is animated", img_path.display()));
+                errors.fetch_add(1, Ordering::Relaxed);
+            } else {
+                log.info(format!("Would skip animated {}", img_path.display()));
+            }
+            pb.inc(1);
+            return;
+        }
+        // 哈希只记录在内存中，这样同一批里的重复图片也能被识别出来
+        let result = lookup(
+            context,
+            &data,
+            options.distance_threshold,
+            histogram.is_some(),
+        )
+        .map(|looked_up| {
+            if let Some(histogram) = histogram {
+                histogram.record(looked_up.nearest);
+            }
+            match looked_up.decision {
+                // 内存中的存储不会写入失败
+                HashDecision::Novel(_) => context
+                    .try_insert_hash(
+                        &looked_up.hashes,
+                        options.distance_threshold,
+                        &HashMeta::default(),
+                    )
+                    .unwrap(),
+                HashDecision::Duplicate { of, distance } => Inserted::Duplicate { of, distance },
+            }
+        });
+        match result {
+            Ok(Inserted::Stored(_)) => {
+                log.info(format!("Would convert {}", img_path.display()));
+            }
+            Ok(_) => {
+                log.info(format!("Would skip duplicate {}", img_path.display()));
+            }
+            Err(e) => {
+                log.error(format!("Image {} error: {:?}", img_path.display(), e));
+                errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        pb.inc(1);
+    });
+
+    pb.finish_with_message("Dry run complete");
+    errors.into_inner()
+}
+
+// 预览图中每张缩略图所占的格子大小
+const CONTACT_SHEET_CELL: u32 = 256;
+
+#[derive(Serialize)]
+struct DuplicateSetRow {
+    group: usize,
+    path: String,
+    bytes: u64,
+    width: u32,
+    height: u32,
+    // 与组内第一张图片的距离
+    distance: u32,
+}
+
+// 按路径顺序分组，与已有组的第一张图片距离不超过阈值的归入该组，返回出错的数量
+fn find_duplicates(
+    context: &Context,
+    images: &[PathBuf],
+    distance_threshold: u32,
+    report: &Path,
+    contact_sheets: Option<&Path>,
+    verbosity: Verbosity,
+    progress: &Progress,
+) -> u64 {
+    let pb = init_pb(images.len(), progress.template);
+    progress.start(&pb, verbosity);
+    let log = Log { pb: &pb, verbosity };
+    let errors = AtomicU64::new(0);
+    let hashes: Vec<(&PathBuf, ImageHash)> = images
+        .par_iter()
+        .filter_map(|img_path| {
+            let hashed = context.hash_image(img_path);
+            pb.inc(1);
+            match hashed {
+                Ok(hash) => Some((img_path, hash)),
+                Err(e) => {
+                    log.error(format!("Image {} error: {:?}", img_path.display(), e));
+                    errors.fetch_add(1, Ordering::Relaxed);
+                    None
+                }
+            }
+        })
+        .collect();
+    pb.finish_and_clear();
+
+    // 每组第一张图片的哈希，按它在树中的编号找到组号
+    let mut firsts = BkTree::new();
+    let mut group_of: HashMap<usize, usize> = HashMap::new();
+    let mut groups: Vec<Vec<(&PathBuf, u32)>> = Vec::new();
+    for (img_path, hash) in hashes {
+        match firsts.query_within(&hash, distance_threshold) {
+            Some((first, distance)) => groups[group_of[&first]].push((img_path, distance)),
+            None => {
+                group_of.insert(firsts.insert(hash), groups.len());
+                groups.push(vec![(img_path, 0)]);
+            }
+        }
+    }
+    groups.retain(|group| group.len() > 1);
+
+    write_duplicate_sets(report, &groups)
+        .unwrap_or_else(|e| exit_fatal(format!("Failed to write {}: {}", report.display(), e)));
+    println!(
+        "Found {} duplicate sets covering {} images, written to {}",
+        groups.len(),
+        groups.iter().map(Vec::len).sum::<usize>(),
+        report.display()
+    );
+
+    if let Some(dir) = contact_sheets {
+        std::fs::create_dir_all(dir)
+            .unwrap_or_else(|e| exit_fatal(format!("Failed to create {}: {}", dir.display(), e)));
+        let pb = init_pb(groups.len(), progress.template);
+        progress.start(&pb, verbosity);
+        let log = Log { pb: &pb, verbosity };
+        let written = AtomicU64::new(0);
+        groups.par_iter().enumerate().for_each(|(group, members)| {
+            let path = dir.join(format!("group-{:04}.png", group + 1));
+            let paths: Vec<&Path> = members.iter().map(|(p, _)| p.as_path()).collect();
+            match context
+                .contact_sheet(&paths, CONTACT_SHEET_CELL)
+                .and_then(|sheet| sheet.save(&path))
+            {
+                Ok(()) => {
+                    written.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    log.error(format!("Failed to write {}: {}", path.display(), e));
+                    errors.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            pb.inc(1);
+        });
+        pb.finish_and_clear();
+        println!(
+            "Wrote {} contact sheets to {}",
+            written.into_inner(),
+            dir.display()
+        );
+    }
+    errors.into_inner()
+}
+
+// 每组一行一张图片，组号从1开始
+fn write_duplicate_sets(report: &Path, groups: &[Vec<(&PathBuf, u32)>]) -> std::io::Result<()> {
+    let mut writer = csv::Writer::from_path(report)?;
+    for (group, members) in groups.iter().enumerate() {
+        for (img_path, distance) in members {
+            let (width, height) = image::image_dimensions(img_path).unwrap_or((0, 0));
+            writer.serialize(DuplicateSetRow {
+                group: group + 1,
+                path: img_path.display().to_string(),
+                bytes: std::fs::metadata(img_path).map_or(0, |m| m.len()),
+                width,
+                height,
+                distance: *distance,
+            })?;
+        }
+    }
+    writer.flush()
+}
+
+#[derive(Serialize)]
+struct ReportRow {
+    duplicate: String,
+    distance: u32,
+    kept_hash: String,
+    kept_source: String,
+    kept_output: String,
+}
+
+// 按记录编号把重复图片对应回保留的图片，清单里没有的记录（比如参考哈希）没有对应路径
+fn write_report(
+    report: &Path,
+    context: &Context,
+    kept: &HashMap<usize, Kept>,
+    mut duplicates: Vec<(PathBuf, usize, u32)>,
+) -> std::io::Result<()> {
+    duplicates.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut writer = csv::Writer::from_path(report)?;
+    for (source, of, distance) in duplicates {
+        let entry = kept.get(&of);
+        writer.serialize(ReportRow {
+            duplicate: source.display().to_string(),
+            distance,
+            kept_hash: context
+                .stored_hash(of)
+                .map(|hash| hash.to_base64())
+                .unwrap_or_default(),
+            kept_source: entry.map(|e| e.source.clone()).unwrap_or_default(),
+            kept_output: entry
+                .map(|e| e.output.display().to_string())
+                .unwrap_or_default(),
+        })?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_lists_split_on_newlines_and_nuls() {
+        let paths = list_paths(b"a.png\r\n  b c.jpg \n\n./d.webp\0e.png\0");
+        assert_eq!(
+            paths,
+            ["a.png", "b c.jpg", "./d.webp", "e.png"].map(PathBuf::from)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn path_lists_keep_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+        let paths = list_paths(b"caf\xe9.png\0");
+        assert_eq!(paths[0].as_os_str().as_bytes(), b"caf\xe9.png");
+    }
+
+    fn unix(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn since_accepts_ages() {
+        // 系统时钟可能被调整，只检查差距在一分钟以内
+        let expected = SystemTime::now() - Duration::from_secs(7 * 86400);
+        let since = parse_since("7d").unwrap();
+        let gap = since
+            .duration_since(expected)
+            .unwrap_or_else(|e| e.duration());
+        assert!(gap < Duration::from_secs(60));
+        assert_eq!(parse_since(" 2024-05-01 "), Ok(unix(1_714_521_600)));
+        assert!(parse_since("3x").is_err());
+        assert!(parse_since("yesterday").is_err());
+    }
+
+    #[test]
+    fn rfc3339_times_are_converted_to_utc() {
+        assert_eq!(
+            parse_rfc3339("2024-05-01T08:00:00Z"),
+            Some(unix(1_714_550_400))
+        );
+        assert_eq!(
+            parse_rfc3339("2024-05-01T16:00:00+08:00"),
+            Some(unix(1_714_550_400))
+        );
+        assert_eq!(
+            parse_rfc3339("2024-05-01T08:00:00.5Z"),
+            Some(unix(1_714_550_400) + Duration::from_millis(500))
+        );
+        assert_eq!(parse_rfc3339("2024-05-01"), Some(unix(1_714_521_600)));
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(unix(0)));
+    }
+
+    #[test]
+    fn leap_days_are_valid_only_in_leap_years() {
+        assert_eq!(parse_rfc3339("2024-02-29"), Some(unix(1_709_164_800)));
+        assert_eq!(utc_date(unix(1_709_164_800 + 86399)), "2024-02-29");
+        assert_eq!(utc_date(unix(1_709_164_800 + 86400)), "2024-03-01");
+        assert_eq!(parse_rfc3339("2023-02-29"), None);
+        assert_eq!(parse_rfc3339("1900-02-29"), None);
+        assert!(parse_rfc3339("2000-02-29").is_some());
+    }
+
+    #[test]
+    fn invalid_times_are_rejected() {
+        for s in [
+            "",
+            "2024-13-01",
+            "2024-05-32",
+            "2024-5-1",
+            "2024-05-01T25:00:00Z",
+            "2024-05-01T08:00:00",
+            "2024-05-01T08:00Z",
+            "2024-05-01T08:00:00+0800",
+        ] {
+            assert_eq!(parse_rfc3339(s), None, "{}", s);
+        }
+    }
+
+    fn numbered(count: usize) -> Vec<PathBuf> {
+        (0..count)
+            .map(|i| PathBuf::from(format!("{:03}.png", i)))
+            .collect()
+    }
+
+    #[test]
+    fn sample_is_reproducible_with_a_seed() {
+        let mut first = numbered(100);
+        let mut second = numbered(100);
+        sample(&mut first, 10, 42);
+        sample(&mut second, 10, 42);
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 10);
+        // 结果保持原来的顺序且没有重复
+        assert!(first.windows(2).all(|w| w[0] < w[1]));
+        assert!(first.iter().all(|p| numbered(100).contains(p)));
+
+        let mut other = numbered(100);
+        sample(&mut other, 10, 43);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn sample_keeps_everything_when_asking_for_more() {
+        for n in [5, 6, 1000] {
+            let mut images = numbered(5);
+            sample(&mut images, n, 1);
+            assert_eq!(images, numbered(5));
+        }
+        let mut images: Vec<PathBuf> = Vec::new();
+        sample(&mut images, 3, 1);
+        assert!(images.is_empty());
+    }
+
+    #[test]
+    fn name_template_fills_placeholders() {
+        let template: NameTemplate = "converted/{stem}_{index}".parse().unwrap();
+        assert_eq!(
+            template.render(Path::new("photos/beach.jpg"), 7),
+            "converted/beach_7"
+        );
+        let template: NameTemplate = "{date}/{uuid}".parse().unwrap();
+        let name = template.render(Path::new("beach.jpg"), 0);
+        let (date, uuid) = name.split_once('/').unwrap();
+        assert_eq!(date, utc_date(SystemTime::now()));
+        assert_eq!(uuid::Uuid::parse_str(uuid).unwrap().get_version_num(), 7);
+    }
+
+    #[test]
+    fn name_template_rejects_bad_templates() {
+        let error = "{stem}_{name}".parse::<NameTemplate>().err().unwrap();
+        assert!(error.starts_with("unknown placeholder {name}"), "{}", error);
+        assert!("{stem".parse::<NameTemplate>().is_err());
+        for path in ["", "/abs/{uuid}", "../{uuid}", "a/../{uuid}"] {
+            assert!(path.parse::<NameTemplate>().is_err(), "{}", path);
+        }
+    }
+
+    // 按命令行和配置文件解析convert的参数
+    fn convert_args(dir: &Path, config: &str) -> ConvertArgs {
+        let path = dir.join("convert.toml");
+        std::fs::write(&path, config).unwrap();
+        let matches = Cli::command()
+            .try_get_matches_from(["convert_img", "convert", "--config", path.to_str().unwrap()])
+            .unwrap();
+        let Some(Command::Convert(mut args)) = Cli::from_arg_matches(&matches).unwrap().command
+        else {
+            unreachable!();
+        };
+        args.apply_config(matches.subcommand_matches("convert").unwrap());
+        *args
+    }
+
+    #[test]
+    fn most_specific_override_wins() {
+        let dir =
+            std::env::temp_dir().join(format!("convert_img_overrides_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let args = convert_args(
+            &dir,
+            r#"
+            [[override]]
+            glob = "**/shots/*.png"
+            quality = 90
+
+            [[override]]
+            extension = "PNG"
+            quality = 50
+            speed = 8
+
+            [[override]]
+            glob = "**/*.png"
+            speed = 3
+
+            [[override]]
+            glob = "**/*.jpg"
+            speed = 2
+
+            [[override]]
+            glob = "**/x.jpg"
+            speed = 9
+            "#,
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+        let options = ConvertOptions {
+            speed: 5,
+            quality: 60,
+            distance_threshold: 0,
+            output_format: OutputFormat::Avif,
+            max_width: None,
+            max_height: None,
+            keep_metadata: false,
+            thumbnail: None,
+            target_size: None,
+        };
+        let resolved = |path: &str| {
+            let options = resolve_overrides(&args.overrides, Path::new(path), options);
+            (options.quality, options.speed)
+        };
+        // glob优先于扩展名，更长的glob优先
+        assert_eq!(resolved("a/shots/b.png"), (90, 3));
+        assert_eq!(resolved("a/b.PNG"), (50, 8));
+        assert_eq!(resolved("a/b.png"), (50, 3));
+        // 同样长的glob中文件里靠后的优先
+        assert_eq!(resolved("a/x.jpg"), (60, 9));
+        assert_eq!(resolved("a/y.jpg"), (60, 2));
+        assert_eq!(resolved("a/b.webp"), (60, 5));
+    }
+
+    #[test]
+    fn panics_and_timeouts_are_reported() {
+        let panics = || -> u32 { panic!("broken image") };
+        assert_eq!(with_timeout(None, panics), Err(Unfinished::Panicked));
+        let timeout = Some(Duration::from_secs(10));
+        assert_eq!(with_timeout(timeout, panics), Err(Unfinished::Panicked));
+        assert_eq!(with_timeout(timeout, || 1), Ok(1));
+        let slow = || std::thread::sleep(Duration::from_secs(1));
+        let timeout = Some(Duration::from_millis(10));
+        assert_eq!(with_timeout(timeout, slow), Err(Unfinished::TimedOut));
+    }
+
+    #[test]
+    fn summary_lists_handled_sources() {
+        let summary = Summary::default();
+        summary
+            .sources_handled
+            .lock()
+            .unwrap()
+            .push((PathBuf::from("b.png"), Some(PathBuf::from("done/b.png"))));
+        summary
+            .sources_handled
+            .lock()
+            .unwrap()
+            .push((PathBuf::from("a.png"), Some(PathBuf::from("done/a.png"))));
+        let mut out = Vec::new();
+        summary
+            .print_sources(&mut out, Some(Path::new("done")))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Moved 2 source images to done\n  a.png -> done/a.png\n  b.png -> done/b.png\n"
+        );
+    }
+
+    #[test]
+    fn histogram_bounds_follow_the_hash_size() {
+        assert_eq!(Histogram::new(64).bounds, [2, 5, 9, 15, 24, 40, 64]);
+        assert_eq!(Histogram::new(16).bounds, [2, 5, 9, 15, 16]);
+
+        // 默认的64x64哈希有4096位，远距离的图片不能都挤在一个桶里
+        let histogram = Histogram::new(4096);
+        assert_eq!(
+            histogram.bounds,
+            [2, 5, 9, 15, 24, 40, 64, 128, 256, 512, 1024, 2048, 4096]
+        );
+        for distance in [0, 300, 1500, 4096] {
+            histogram.record(Some(distance));
+        }
+        histogram.record(None);
+        let mut out = Vec::new();
+        histogram.print(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("     0-2       1 #"), "{}", out);
+        assert!(out.contains(" 257-512       1 #"), "{}", out);
+        assert!(out.contains("1025-2048       1 #"), "{}", out);
+        assert!(out.contains("2049-4096       1 #"), "{}", out);
+        assert!(out.ends_with("  1 images had no stored hash to compare with\n"));
+    }
 }
@@ -0,0 +1,42 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+/// 计数信号量，限制同时进行的任务数量
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    cvar: Condvar,
+}
+
+/// 持有期间占用一个许可，离开作用域时归还
+///
+/// 许可持有信号量的所有权，可以移到其他线程，比如超时后仍在运行的任务中
+pub struct SemaphorePermit {
+    semaphore: Arc<Semaphore>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: Mutex::new(permits),
+            cvar: Condvar::new(),
+        }
+    }
+
+    /// 阻塞直到拿到许可
+    pub fn acquire(self: &Arc<Self>) -> SemaphorePermit {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.cvar.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        SemaphorePermit {
+            semaphore: self.clone(),
+        }
+    }
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        *self.semaphore.permits.lock().unwrap() += 1;
+        self.semaphore.cvar.notify_one();
+    }
+}
@@ -0,0 +1,966 @@
+use crate::{
+    BkTree, HashSize, append_line, hashes_header, io_context, validate_hashes_header, write_output,
+};
+use clap::ValueEnum;
+use image_hasher::{HashAlg, ImageHash};
+#[cfg(feature = "sqlite")]
+use rusqlite::Connection;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "sqlite")]
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+#[cfg(feature = "sqlite")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// 哈希存储的类型
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum StoreKind {
+    /// 每行一个base64哈希的文本文件
+    #[value(help = "Text file with one base64 hash per line")]
+    Flat,
+    /// SQLite数据库，同时记录源文件、输出文件和时间，需要启用`sqlite`特性
+    #[value(help = "SQLite database that also records source, output and time")]
+    Sqlite,
+    /// 只保存在内存中的BK树，不读取也不写入任何文件
+    #[value(help = "Keep hashes in memory only, without reading or writing a file")]
+    Memory,
+}
+
+impl StoreKind {
+    /// 未指定路径时使用的文件名
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            StoreKind::Flat | StoreKind::Memory => "hashes",
+            StoreKind::Sqlite => "hashes.db",
+        }
+    }
+}
+
+/// 与哈希一起保存的信息，文本文件只保存哈希本身
+#[derive(Clone, Default)]
+pub struct HashMeta {
+    pub source: Option<String>,
+    /// 相对于输出目录的路径
+    pub output: Option<String>,
+    pub distance_threshold: u32,
+}
+
+/// [`HashStore::load`]读取的记录数
+#[derive(Clone, Copy, Default)]
+pub struct Loaded {
+    pub hashes: usize,
+    /// 无法解析为哈希而被跳过的记录，文件损坏或被截断时出现
+    pub malformed: usize,
+}
+
+/// 找到的相似哈希
+pub struct Match {
+    /// 已存记录的编号，用[`HashStore::get`]取得哈希，用[`HashStore::replace`]替换
+    ///
+    /// 编号只在本次运行中有效，[`HashStore::replace_all`]之后重新分配
+    pub id: usize,
+    pub distance: u32,
+}
+
+/// 哈希存储，负责查找相似哈希和持久化
+pub trait HashStore: Send + Sync {
+    /// 读取已保存的所有哈希，无法解析的记录跳过并计入[`Loaded::malformed`]
+    fn load(&mut self) -> std::io::Result<Loaded>;
+
+    /// 查找任意一个与`hash`距离不超过`threshold`的已存哈希
+    fn contains_near(&self, hash: &ImageHash, threshold: u32) -> Option<Match>;
+
+    /// 最相似的已存哈希，存储为空时返回`None`
+    fn nearest(&self, hash: &ImageHash) -> Option<Match>;
+
+    /// 编号为`id`的已存哈希，已被替换时返回`None`
+    fn get(&self, id: usize) -> Option<&ImageHash>;
+
+    /// 记录一个新的哈希，返回它的编号
+    fn insert(&mut self, hash: ImageHash, meta: &HashMeta) -> std::io::Result<usize>;
+
+    /// 用`hash`替换编号为`old`的已存哈希并返回新记录的编号，保留更好的重复图片时使用
+    fn replace(&mut self, old: usize, hash: ImageHash, meta: &HashMeta) -> std::io::Result<usize>;
+
+    /// 删除编号为`id`的已存哈希，输出没有写入时撤销刚记录的哈希
+    fn remove(&mut self, id: usize) -> std::io::Result<()>;
+
+    /// 按写入顺序列出所有记录，文本文件不保存哈希以外的信息
+    fn records(&mut self) -> std::io::Result<Vec<(ImageHash, HashMeta)>>;
+
+    /// 清空已有记录后写入`records`，用于重建
+    fn replace_all(&mut self, records: Vec<(ImageHash, HashMeta)>) -> std::io::Result<()>;
+
+    /// 把缓冲中的记录写入文件，但不等待落盘。不缓冲的存储什么也不做
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// 写入缓冲中的记录并确保落盘
+    fn sync(&mut self) -> std::io::Result<()>;
+
+    /// 只保留内存中的哈希，之后的插入不再写入文件
+    fn into_memory(self: Box<Self>) -> BkTree;
+}
+
+/// 打开`path`处的存储，检查生成哈希时的算法和尺寸并读取已有哈希
+///
+/// 文件在第一次写入时才创建，只读取时不会留下空文件。设置不一致时返回错误，`force`为`true`时只打印警告
+pub fn open_store(
+    kind: StoreKind,
+    path: &Path,
+    hash_alg: HashAlg,
+    hash_size: HashSize,
+    force: bool,
+) -> std::io::Result<Box<dyn HashStore>> {
+    let mut store: Box<dyn HashStore> = match kind {
+        StoreKind::Flat => Box::new(FlatFileStore::open(path, hash_alg, hash_size, force)?),
+        #[cfg(feature = "sqlite")]
+        StoreKind::Sqlite => Box::new(SqliteStore::open(path, hash_alg, hash_size, force)?),
+        #[cfg(not(feature = "sqlite"))]
+        StoreKind::Sqlite => return Err(sqlite_unsupported()),
+        StoreKind::Memory => Box::new(BkTree::new()),
+    };
+    let loaded = store
+        .load()
+        .map_err(|e| io_context(e, "load hashes from", path))?;
+    warn_malformed(path, loaded);
+    Ok(store)
+}
+
+// 存储中有无法解析的记录时提示，否则查重会在用户不知情时漏掉这部分哈希
+fn warn_malformed(path: &Path, loaded: Loaded) {
+    if loaded.malformed > 0 {
+        eprintln!(
+            "Warning: skipped {} malformed entries in {}, loaded {} hashes. \
+             The file may be corrupted or truncated; run rebuild to regenerate it",
+            loaded.malformed,
+            path.display(),
+            loaded.hashes
+        );
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn sqlite_unsupported() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "convert_img was built without the sqlite feature",
+    )
+}
+
+/// 存储的概况
+pub struct StoreInfo {
+    /// 记录生成哈希时的算法和尺寸，旧文件没有
+    pub header: Option<String>,
+    pub hashes: usize,
+}
+
+/// 不检查设置直接读取存储的概况，文件不存在或是内存存储时返回`None`
+pub fn store_info(kind: StoreKind, path: &Path) -> std::io::Result<Option<StoreInfo>> {
+    if kind == StoreKind::Memory || !path.exists() {
+        return Ok(None);
+    }
+    let info = match kind {
+        #[cfg(not(feature = "sqlite"))]
+        StoreKind::Sqlite => return Err(sqlite_unsupported()),
+        #[cfg(feature = "sqlite")]
+        StoreKind::Sqlite => {
+            let conn = Connection::open(path).map_err(std::io::Error::other)?;
+            StoreInfo {
+                header: conn
+                    .query_row("SELECT value FROM meta WHERE key = 'header'", [], |row| {
+                        row.get(0)
+                    })
+                    .ok(),
+                hashes: conn
+                    .query_row("SELECT COUNT(*) FROM hashes", [], |row| {
+                        row.get::<_, i64>(0)
+                    })
+                    .map_err(std::io::Error::other)? as usize,
+            }
+        }
+        _ => {
+            let content = std::fs::read_to_string(path)?;
+            StoreInfo {
+                header: content
+                    .lines()
+                    .next()
+                    .filter(|l| l.starts_with('#'))
+                    .map(String::from),
+                hashes: content
+                    .lines()
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .count(),
+            }
+        }
+    };
+    Ok(Some(info))
+}
+
+impl HashStore for BkTree {
+    fn load(&mut self) -> std::io::Result<Loaded> {
+        Ok(Loaded::default())
+    }
+
+    fn contains_near(&self, hash: &ImageHash, threshold: u32) -> Option<Match> {
+        self.query_within(hash, threshold)
+            .map(|(id, distance)| Match { id, distance })
+    }
+
+    fn nearest(&self, hash: &ImageHash) -> Option<Match> {
+        BkTree::nearest(self, hash).map(|(id, distance)| Match { id, distance })
+    }
+
+    fn get(&self, id: usize) -> Option<&ImageHash> {
+        BkTree::get(self, id)
+    }
+
+    fn insert(&mut self, hash: ImageHash, _meta: &HashMeta) -> std::io::Result<usize> {
+        Ok(BkTree::insert(self, hash))
+    }
+
+    fn replace(&mut self, old: usize, hash: ImageHash, _meta: &HashMeta) -> std::io::Result<usize> {
+        self.remove(old);
+        Ok(BkTree::insert(self, hash))
+    }
+
+    fn remove(&mut self, id: usize) -> std::io::Result<()> {
+        BkTree::remove(self, id);
+        Ok(())
+    }
+
+    fn records(&mut self) -> std::io::Result<Vec<(ImageHash, HashMeta)>> {
+        Ok(self
+            .hashes()
+            .map(|hash| (hash.clone(), HashMeta::default()))
+            .collect())
+    }
+
+    fn replace_all(&mut self, records: Vec<(ImageHash, HashMeta)>) -> std::io::Result<()> {
+        *self = BkTree::new();
+        for (hash, _) in records {
+            BkTree::insert(self, hash);
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn into_memory(self: Box<Self>) -> BkTree {
+        *self
+    }
+}
+
+/// 读取一个或多个hashes文本文件作为只读的参考哈希，文件头同样需要与当前设置一致
+pub fn load_reference_hashes(
+    paths: &[PathBuf],
+    hash_alg: HashAlg,
+    hash_size: HashSize,
+    force: bool,
+) -> std::io::Result<BkTree> {
+    let mut reference = BkTree::new();
+    for path in paths {
+        if !path.is_file() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Reference hashes file {} does not exist", path.display()),
+            ));
+        }
+        let mut store = FlatFileStore::open(path, hash_alg, hash_size, force)?;
+        let loaded = store
+            .load()
+            .map_err(|e| io_context(e, "load hashes from", path))?;
+        warn_malformed(path, loaded);
+        for hash in std::mem::take(&mut store.index).into_hashes() {
+            reference.insert(hash);
+        }
+    }
+    Ok(reference)
+}
+
+/// 在另一个存储之外再用只读的参考哈希查重，新哈希只写入内层存储
+pub struct ReferenceStore {
+    inner: Box<dyn HashStore>,
+    reference: BkTree,
+}
+
+// 参考哈希的编号设置最高位，与内层存储的编号区分
+const REFERENCE_ID: usize = 1 << (usize::BITS - 1);
+
+impl ReferenceStore {
+    pub fn new(inner: Box<dyn HashStore>, reference: BkTree) -> Self {
+        ReferenceStore { inner, reference }
+    }
+
+    fn reference_match(m: Match) -> Match {
+        Match {
+            id: m.id | REFERENCE_ID,
+            ..m
+        }
+    }
+}
+
+impl HashStore for ReferenceStore {
+    fn load(&mut self) -> std::io::Result<Loaded> {
+        self.inner.load()
+    }
+
+    fn contains_near(&self, hash: &ImageHash, threshold: u32) -> Option<Match> {
+        self.reference
+            .contains_near(hash, threshold)
+            .map(Self::reference_match)
+            .or_else(|| self.inner.contains_near(hash, threshold))
+    }
+
+    fn nearest(&self, hash: &ImageHash) -> Option<Match> {
+        let reference = HashStore::nearest(&self.reference, hash).map(Self::reference_match);
+        match (reference, self.inner.nearest(hash)) {
+            (Some(a), Some(b)) => Some(if a.distance <= b.distance { a } else { b }),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn get(&self, id: usize) -> Option<&ImageHash> {
+        if id & REFERENCE_ID != 0 {
+            self.reference.get(id & !REFERENCE_ID)
+        } else {
+            self.inner.get(id)
+        }
+    }
+
+    fn insert(&mut self, hash: ImageHash, meta: &HashMeta) -> std::io::Result<usize> {
+        self.inner.insert(hash, meta)
+    }
+
+    fn replace(&mut self, old: usize, hash: ImageHash, meta: &HashMeta) -> std::io::Result<usize> {
+        if old & REFERENCE_ID != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "reference hashes are read-only",
+            ));
+        }
+        self.inner.replace(old, hash, meta)
+    }
+
+    fn remove(&mut self, id: usize) -> std::io::Result<()> {
+        if id & REFERENCE_ID != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "reference hashes are read-only",
+            ));
+        }
+        self.inner.remove(id)
+    }
+
+    // 参考哈希是只读的，不属于这个存储
+    fn records(&mut self) -> std::io::Result<Vec<(ImageHash, HashMeta)>> {
+        self.inner.records()
+    }
+
+    fn replace_all(&mut self, records: Vec<(ImageHash, HashMeta)>) -> std::io::Result<()> {
+        self.inner.replace_all(records)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn into_memory(self: Box<Self>) -> BkTree {
+        let mut tree = self.inner.into_memory();
+        for hash in self.reference.into_hashes() {
+            tree.insert(hash);
+        }
+        tree
+    }
+}
+
+/// 兼容旧版本的hashes文本文件
+///
+/// 新哈希先在内存中缓冲，攒够一批或间隔一段时间后才追加到文件。插入停止后缓冲不会自动写入，
+/// 需要每隔[`HASH_FLUSH_INTERVAL`]调用一次[`HashStore::flush`]，[`HashStore::sync`]和drop时也会写入剩余部分
+pub struct FlatFileStore {
+    path: PathBuf,
+    header: String,
+    file: Option<File>,
+    index: BkTree,
+    // 还没有追加到文件的哈希及其中第一个的插入时间
+    pending: Vec<String>,
+    pending_since: Option<Instant>,
+}
+
+// 缓冲的哈希达到这个数量后写入文件
+const FLUSH_LINES: usize = 64;
+
+/// [`FlatFileStore`]缓冲哈希的最长时间，插入时超过这个时间就写入文件
+pub const HASH_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+impl FlatFileStore {
+    pub fn open(
+        path: &Path,
+        hash_alg: HashAlg,
+        hash_size: HashSize,
+        force: bool,
+    ) -> std::io::Result<Self> {
+        if let Ok(content) = std::fs::read_to_string(path) {
+            validate_hashes_header(path, &content, hash_alg, hash_size, force)?;
+        }
+        Ok(FlatFileStore {
+            path: path.to_path_buf(),
+            header: hashes_header(hash_alg, hash_size),
+            file: None,
+            index: BkTree::new(),
+            pending: Vec::new(),
+            pending_since: None,
+        })
+    }
+
+    // 第一次写入时以追加方式打开，新文件先写入文件头
+    fn file(&mut self) -> std::io::Result<&mut File> {
+        if self.file.is_none() {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            if file.metadata()?.len() == 0 {
+                writeln!(file, "{}", self.header)?;
+            }
+            self.file = Some(file);
+        }
+        Ok(self.file.as_mut().unwrap())
+    }
+
+    // 整个重写到临时文件再替换，写入失败时原文件保持不变。缓冲的哈希已在索引中，一并写入
+    fn rewrite(&mut self) -> std::io::Result<()> {
+        self.pending.clear();
+        self.pending_since = None;
+        let mut content = format!("{}\n", self.header);
+        for hash in self.index.hashes() {
+            content.push_str(&hash.to_base64());
+            content.push('\n');
+        }
+        // 之后的追加写入重新打开文件
+        self.file = None;
+        write_output(&self.path, content.as_bytes(), 0)
+    }
+}
+
+// 没有sync就结束时，也不丢失缓冲的哈希
+impl Drop for FlatFileStore {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl HashStore for FlatFileStore {
+    fn load(&mut self) -> std::io::Result<Loaded> {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Loaded::default()),
+            Err(e) => return Err(e),
+        };
+        let mut loaded = Loaded::default();
+        for line in content
+            .lines()
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        {
+            match ImageHash::from_base64(line) {
+                Ok(hash) => {
+                    self.index.insert(hash);
+                    loaded.hashes += 1;
+                }
+                Err(_) => loaded.malformed += 1,
+            }
+        }
+        Ok(loaded)
+    }
+
+    fn contains_near(&self, hash: &ImageHash, threshold: u32) -> Option<Match> {
+        self.index.contains_near(hash, threshold)
+    }
+
+    fn nearest(&self, hash: &ImageHash) -> Option<Match> {
+        HashStore::nearest(&self.index, hash)
+    }
+
+    fn get(&self, id: usize) -> Option<&ImageHash> {
+        self.index.get(id)
+    }
+
+    fn insert(&mut self, hash: ImageHash, _meta: &HashMeta) -> std::io::Result<usize> {
+        self.pending.push(hash.to_base64());
+        let id = self.index.insert(hash);
+        let since = *self.pending_since.get_or_insert_with(Instant::now);
+        if self.pending.len() >= FLUSH_LINES || since.elapsed() >= HASH_FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(id)
+    }
+
+    // 文本文件无法删除单行，替换后整个重写
+    fn replace(&mut self, old: usize, hash: ImageHash, _meta: &HashMeta) -> std::io::Result<usize> {
+        self.index.remove(old);
+        let id = self.index.insert(hash);
+        self.rewrite()?;
+        Ok(id)
+    }
+
+    // 还在缓冲中的哈希直接丢弃，已经写入文件的需要整个重写
+    fn remove(&mut self, id: usize) -> std::io::Result<()> {
+        let Some(base64) = self.index.get(id).map(ImageHash::to_base64) else {
+            return Ok(());
+        };
+        self.index.remove(id);
+        match self.pending.iter().position(|line| *line == base64) {
+            Some(index) => {
+                self.pending.remove(index);
+                if self.pending.is_empty() {
+                    self.pending_since = None;
+                }
+                Ok(())
+            }
+            None => self.rewrite(),
+        }
+    }
+
+    // 从文件读取，索引里重复的哈希只保存一份
+    fn records(&mut self) -> std::io::Result<Vec<(ImageHash, HashMeta)>> {
+        self.flush()?;
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(content
+            .lines()
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(|l| ImageHash::from_base64(l).ok())
+            .map(|hash| (hash, HashMeta::default()))
+            .collect())
+    }
+
+    fn replace_all(&mut self, records: Vec<(ImageHash, HashMeta)>) -> std::io::Result<()> {
+        self.index = BkTree::new();
+        for (hash, _) in records {
+            self.index.insert(hash);
+        }
+        self.rewrite()
+    }
+
+    // 把缓冲的哈希一次追加到文件，减少持有写锁时的系统调用。写入失败时保留缓冲，之后重试
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let lines = self.pending.join("\n");
+        append_line(self.file()?, &lines)?;
+        self.pending.clear();
+        self.pending_since = None;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        match &self.file {
+            Some(file) => file.sync_all(),
+            None => Ok(()),
+        }
+    }
+
+    fn into_memory(mut self: Box<Self>) -> BkTree {
+        std::mem::take(&mut self.index)
+    }
+}
+
+/// SQLite存储，文件头保存在meta表的header字段中
+///
+/// 需要启用`sqlite`特性
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore {
+    path: PathBuf,
+    header: String,
+    // Connection不能在线程间共享，用Mutex包装
+    conn: Mutex<Option<Connection>>,
+    index: BkTree,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    pub fn open(
+        path: &Path,
+        hash_alg: HashAlg,
+        hash_size: HashSize,
+        force: bool,
+    ) -> std::io::Result<Self> {
+        let header = hashes_header(hash_alg, hash_size);
+        let sqlite_error = |e| io_context(std::io::Error::other(e), "open", path);
+        let conn = if path.exists() {
+            let conn = Connection::open(path).map_err(sqlite_error)?;
+            let stored: Option<String> = conn
+                .query_row("SELECT value FROM meta WHERE key = 'header'", [], |row| {
+                    row.get(0)
+                })
+                .ok();
+            // 没有文件头时按旧的默认设置检查
+            validate_hashes_header(
+                path,
+                stored.as_deref().unwrap_or(""),
+                hash_alg,
+                hash_size,
+                force,
+            )?;
+            init_schema(&conn, &header).map_err(sqlite_error)?;
+            Some(conn)
+        } else {
+            None
+        };
+        Ok(SqliteStore {
+            path: path.to_path_buf(),
+            header,
+            conn: Mutex::new(conn),
+            index: BkTree::new(),
+        })
+    }
+
+    // 第一次写入时才创建数据库
+    fn with_conn<T>(
+        &mut self,
+        f: impl FnOnce(&mut Connection) -> rusqlite::Result<T>,
+    ) -> std::io::Result<T> {
+        let conn = self.conn.get_mut().unwrap();
+        if conn.is_none() {
+            let new_conn = Connection::open(&self.path).map_err(std::io::Error::other)?;
+            init_schema(&new_conn, &self.header).map_err(std::io::Error::other)?;
+            *conn = Some(new_conn);
+        }
+        f(conn.as_mut().unwrap()).map_err(std::io::Error::other)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn init_schema(conn: &Connection, header: &str) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "PRAGMA journal_mode = WAL;
+         CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+         CREATE TABLE IF NOT EXISTS hashes (
+             id INTEGER PRIMARY KEY,
+             hash BLOB NOT NULL,
+             base64 TEXT NOT NULL,
+             source TEXT,
+             output TEXT,
+             distance_threshold INTEGER NOT NULL,
+             created_at INTEGER NOT NULL
+         );",
+    )?;
+    conn.execute(
+        "INSERT OR IGNORE INTO meta (key, value) VALUES ('header', ?1)",
+        [header],
+    )?;
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+fn insert_record(conn: &Connection, hash: &ImageHash, meta: &HashMeta) -> rusqlite::Result<()> {
+    let created_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    conn.execute(
+        "INSERT INTO hashes (hash, base64, source, output, distance_threshold, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        rusqlite::params![
+            hash.as_bytes(),
+            hash.to_base64(),
+            meta.source,
+            meta.output,
+            meta.distance_threshold,
+            created_at
+        ],
+    )?;
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+impl HashStore for SqliteStore {
+    fn load(&mut self) -> std::io::Result<Loaded> {
+        let Some(conn) = self.conn.get_mut().unwrap() else {
+            return Ok(Loaded::default());
+        };
+        let mut statement = conn
+            .prepare("SELECT base64 FROM hashes ORDER BY id")
+            .map_err(std::io::Error::other)?;
+        let rows = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(std::io::Error::other)?;
+        let mut loaded = Loaded::default();
+        for base64 in rows {
+            let base64 = base64.map_err(std::io::Error::other)?;
+            match ImageHash::from_base64(&base64) {
+                Ok(hash) => {
+                    self.index.insert(hash);
+                    loaded.hashes += 1;
+                }
+                Err(_) => loaded.malformed += 1,
+            }
+        }
+        Ok(loaded)
+    }
+
+    fn contains_near(&self, hash: &ImageHash, threshold: u32) -> Option<Match> {
+        self.index.contains_near(hash, threshold)
+    }
+
+    fn nearest(&self, hash: &ImageHash) -> Option<Match> {
+        HashStore::nearest(&self.index, hash)
+    }
+
+    fn get(&self, id: usize) -> Option<&ImageHash> {
+        self.index.get(id)
+    }
+
+    fn insert(&mut self, hash: ImageHash, meta: &HashMeta) -> std::io::Result<usize> {
+        self.with_conn(|conn| insert_record(conn, &hash, meta))?;
+        Ok(self.index.insert(hash))
+    }
+
+    fn replace(&mut self, old: usize, hash: ImageHash, meta: &HashMeta) -> std::io::Result<usize> {
+        let old_base64 = self.index.get(old).map(ImageHash::to_base64);
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            if let Some(old_base64) = &old_base64 {
+                tx.execute("DELETE FROM hashes WHERE base64 = ?1", [old_base64])?;
+            }
+            insert_record(&tx, &hash, meta)?;
+            tx.commit()
+        })?;
+        self.index.remove(old);
+        Ok(self.index.insert(hash))
+    }
+
+    fn remove(&mut self, id: usize) -> std::io::Result<()> {
+        let Some(base64) = self.index.get(id).map(ImageHash::to_base64) else {
+            return Ok(());
+        };
+        self.with_conn(|conn| conn.execute("DELETE FROM hashes WHERE base64 = ?1", [&base64]))?;
+        self.index.remove(id);
+        Ok(())
+    }
+
+    fn records(&mut self) -> std::io::Result<Vec<(ImageHash, HashMeta)>> {
+        let Some(conn) = self.conn.get_mut().unwrap() else {
+            return Ok(Vec::new());
+        };
+        let mut statement = conn
+            .prepare("SELECT base64, source, output, distance_threshold FROM hashes ORDER BY id")
+            .map_err(std::io::Error::other)?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    HashMeta {
+                        source: row.get(1)?,
+                        output: row.get(2)?,
+                        distance_threshold: row.get(3)?,
+                    },
+                ))
+            })
+            .map_err(std::io::Error::other)?;
+        let mut records = Vec::new();
+        for row in rows {
+            let (base64, meta) = row.map_err(std::io::Error::other)?;
+            if let Ok(hash) = ImageHash::from_base64(&base64) {
+                records.push((hash, meta));
+            }
+        }
+        Ok(records)
+    }
+
+    fn replace_all(&mut self, records: Vec<(ImageHash, HashMeta)>) -> std::io::Result<()> {
+        let header = self.header.clone();
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM hashes", [])?;
+            tx.execute(
+                "INSERT OR REPLACE INTO meta (key, value) VALUES ('header', ?1)",
+                [&header],
+            )?;
+            for (hash, meta) in &records {
+                insert_record(&tx, hash, meta)?;
+            }
+            tx.commit()
+        })?;
+        self.index = BkTree::new();
+        for (hash, _) in records {
+            self.index.insert(hash);
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        match self.conn.get_mut().unwrap() {
+            Some(conn) => conn
+                .execute_batch("PRAGMA wal_checkpoint(FULL);")
+                .map_err(std::io::Error::other),
+            None => Ok(()),
+        }
+    }
+
+    fn into_memory(self: Box<Self>) -> BkTree {
+        self.index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_dir;
+
+    const SIZE: HashSize = HashSize {
+        width: 8,
+        height: 8,
+    };
+
+    fn hash(byte: u8) -> ImageHash {
+        ImageHash::from_bytes(&[byte; 8]).unwrap()
+    }
+
+    fn meta(source: &str) -> HashMeta {
+        HashMeta {
+            source: Some(source.to_string()),
+            output: Some(format!("{}.avif", source)),
+            distance_threshold: 2,
+        }
+    }
+
+    fn open(kind: StoreKind, path: &Path) -> Box<dyn HashStore> {
+        open_store(kind, path, HashAlg::Gradient, SIZE, false).unwrap()
+    }
+
+    fn stored(kind: StoreKind, path: &Path) -> Vec<ImageHash> {
+        let mut store = open(kind, path);
+        store
+            .records()
+            .unwrap()
+            .into_iter()
+            .map(|(h, _)| h)
+            .collect()
+    }
+
+    fn round_trip(kind: StoreKind) {
+        let dir = test_dir(&format!("store_{:?}", kind));
+        let path = dir.join(kind.file_name());
+
+        let mut store = open(kind, &path);
+        let first = store.insert(hash(0x00), &meta("a.png")).unwrap();
+        store.insert(hash(0x0f), &meta("b.png")).unwrap();
+        assert_eq!(store.get(first), Some(&hash(0x00)));
+        store.flush().unwrap();
+        // 在关闭前读取，确认flush已把缓冲的哈希写入文件
+        assert_eq!(stored(kind, &path), [hash(0x00), hash(0x0f)]);
+        drop(store);
+
+        let store = open(kind, &path);
+        let found = store.contains_near(&hash(0x0f), 0).unwrap();
+        assert_eq!(store.get(found.id), Some(&hash(0x0f)));
+        assert_eq!(store.nearest(&hash(0x01)).unwrap().distance, 8);
+        drop(store);
+
+        let mut store = open(kind, &path);
+        store
+            .replace_all(vec![(hash(0xff), meta("c.png"))])
+            .unwrap();
+        assert!(store.contains_near(&hash(0x00), 0).is_none());
+        store.sync().unwrap();
+        drop(store);
+
+        assert_eq!(stored(kind, &path), [hash(0xff)]);
+        let mut store = open(kind, &path);
+        let records = store.records().unwrap();
+        if kind == StoreKind::Sqlite {
+            assert_eq!(records[0].1.source.as_deref(), Some("c.png"));
+            assert_eq!(records[0].1.output.as_deref(), Some("c.png.avif"));
+            assert_eq!(records[0].1.distance_threshold, 2);
+        }
+        drop(store);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn flat_store_round_trip() {
+        round_trip(StoreKind::Flat);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_round_trip() {
+        round_trip(StoreKind::Sqlite);
+    }
+
+    fn remove(kind: StoreKind) {
+        let dir = test_dir(&format!("store_remove_{:?}", kind));
+        let path = dir.join(kind.file_name());
+
+        let mut store = open(kind, &path);
+        let written = store.insert(hash(0x00), &meta("a.png")).unwrap();
+        store.flush().unwrap();
+        // 第二个哈希还在缓冲中，第一个已经写入文件
+        let pending = store.insert(hash(0x0f), &meta("b.png")).unwrap();
+        let kept = store.insert(hash(0xff), &meta("c.png")).unwrap();
+        store.remove(pending).unwrap();
+        store.remove(written).unwrap();
+        assert_eq!(store.get(written), None);
+        assert!(store.contains_near(&hash(0x0f), 0).is_none());
+        assert_eq!(store.get(kept), Some(&hash(0xff)));
+        store.sync().unwrap();
+        drop(store);
+
+        assert_eq!(stored(kind, &path), [hash(0xff)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn flat_store_remove() {
+        remove(StoreKind::Flat);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_remove() {
+        remove(StoreKind::Sqlite);
+    }
+
+    #[test]
+    fn reference_store_flushes_the_inner_store() {
+        let dir = test_dir("store_reference_flush");
+        let path = dir.join("hashes");
+        let mut reference = BkTree::new();
+        reference.insert(hash(0xff));
+        let mut store = ReferenceStore::new(open(StoreKind::Flat, &path), reference);
+        store.insert(hash(0x00), &meta("a.png")).unwrap();
+        store.flush().unwrap();
+        // 参考哈希不写入文件，只有内层存储中的哈希
+        assert_eq!(stored(StoreKind::Flat, &path), [hash(0x00)]);
+        drop(store);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mismatched_settings_are_rejected() {
+        let dir = test_dir("store_settings");
+        let path = dir.join("hashes");
+        let mut store = open(StoreKind::Flat, &path);
+        store.insert(hash(0x00), &HashMeta::default()).unwrap();
+        store.sync().unwrap();
+        drop(store);
+        assert!(open_store(StoreKind::Flat, &path, HashAlg::Mean, SIZE, false).is_err());
+        assert!(open_store(StoreKind::Flat, &path, HashAlg::Mean, SIZE, true).is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,157 @@
+use std::path::Path;
+use std::process::Command;
+
+// 写入一张渐变PNG
+fn write_gradient(path: &Path) {
+    image::RgbImage::from_fn(64, 48, |x, y| {
+        image::Rgb([(x * 4) as u8, (y * 5) as u8, 128])
+    })
+    .save(path)
+    .unwrap();
+}
+
+#[test]
+fn identical_images_are_converted_once() {
+    let dir = std::env::temp_dir().join(format!("convert_img_dedup_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let source = dir.join("source");
+    std::fs::create_dir_all(&source).unwrap();
+    // 两份副本并行处理时，只有一份能通过查重
+    write_gradient(&source.join("a.png"));
+    write_gradient(&source.join("b.png"));
+    let output = dir.join("output");
+    let hashes = dir.join("hashes");
+
+    let result = Command::new(env!("CARGO_BIN_EXE_convert_img"))
+        .arg("-s")
+        .arg(&source)
+        .arg("-o")
+        .arg(&output)
+        .arg("--hashes-file-path")
+        .arg(&hashes)
+        .args(["--speed", "10"])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+
+    let outputs = std::fs::read_dir(&output)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "avif"))
+        .count();
+    assert_eq!(outputs, 1);
+    let stored = std::fs::read_to_string(&hashes)
+        .unwrap()
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .count();
+    assert_eq!(stored, 1);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn losing_duplicates_keep_an_overwritten_output() {
+    let dir = std::env::temp_dir().join(format!("convert_img_overwrite_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    // 同名的相同图片写入同一个输出，查重失败的一方不能删掉另一方写入的文件
+    for name in ["a", "b", "c", "d"] {
+        let source = dir.join(name);
+        std::fs::create_dir_all(&source).unwrap();
+        write_gradient(&source.join("image.png"));
+    }
+    let output = dir.join("output");
+    let hashes = dir.join("hashes");
+
+    let mut command = Command::new(env!("CARGO_BIN_EXE_convert_img"));
+    for name in ["a", "b", "c", "d"] {
+        command.arg("-s").arg(dir.join(name));
+    }
+    let result = command
+        .arg("-o")
+        .arg(&output)
+        .arg("--hashes-file-path")
+        .arg(&hashes)
+        .args([
+            "--keep-name",
+            "--on-collision",
+            "overwrite",
+            "--speed",
+            "10",
+            "-t",
+            "4",
+        ])
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+
+    assert!(output.join("image.avif").is_file());
+    let partial = std::fs::read_dir(&output)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "part"))
+        .count();
+    assert_eq!(partial, 0);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn failed_rename_does_not_keep_the_hash() {
+    let dir = std::env::temp_dir().join(format!("convert_img_rename_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let source = dir.join("source");
+    std::fs::create_dir_all(&source).unwrap();
+    write_gradient(&source.join("image.png"));
+    let output = dir.join("output");
+    let hashes = dir.join("hashes");
+    // 输出路径上的非空目录让重命名失败
+    std::fs::create_dir_all(output.join("image.avif").join("blocker")).unwrap();
+
+    let run = || {
+        Command::new(env!("CARGO_BIN_EXE_convert_img"))
+            .arg("-s")
+            .arg(&source)
+            .arg("-o")
+            .arg(&output)
+            .arg("--hashes-file-path")
+            .arg(&hashes)
+            .args([
+                "--keep-name",
+                "--on-collision",
+                "overwrite",
+                "--speed",
+                "10",
+            ])
+            .current_dir(&dir)
+            .output()
+            .unwrap()
+    };
+    let result = run();
+    assert!(!result.status.success());
+    let stored = std::fs::read_to_string(&hashes)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .count();
+    assert_eq!(stored, 0);
+
+    // 哈希已撤销，再次运行时不会被当作重复跳过
+    std::fs::remove_dir_all(output.join("image.avif")).unwrap();
+    let result = run();
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    assert!(output.join("image.avif").is_file());
+    std::fs::remove_dir_all(dir).unwrap();
+}